@@ -28,6 +28,10 @@ impl AppServer {
         let app = Router::new()
             .route("/", get(handlers::get_root))
             .route("/notify_tornado", post(handlers::post_notify_tornado))
+            .route(
+                "/api/internal/notify_tornado",
+                post(handlers::post_notify_tornado),
+            )
             .route(
                 "/json/events",
                 get(handlers::get_events)
@@ -47,6 +51,14 @@ impl AppServer {
                 "/api/v1/events/internal",
                 post(handlers::post_events_internal),
             )
+            .route(
+                "/api/internal/web_reload_clients",
+                post(handlers::post_web_reload_clients),
+            )
+            .route(
+                "/api/internal/rabbitmq_status",
+                post(handlers::post_rabbitmq_status),
+            )
             .with_state(state)
             .layer(middleware::from_fn(print_request_response));
 
@@ -1,15 +1,31 @@
 use std::sync::Mutex;
 
 use crate::avatar::AvatarSettings;
+use crate::outbox::Outbox;
 use crate::queues::Queues;
+use crate::rabbitmq::Publisher;
+use crate::realm::RealmCache;
+use crate::recent_notices::RecentNotices;
+use crate::restart::ServerVersion;
 use crate::shutdown;
+use crate::subdomain::SubdomainSettings;
+use crate::user_activity::UserActivity;
+use crate::validation::NoticeValidator;
 
 pub struct AppState {
     pub shared_secret: String,
     pub secret_key: String,
+    pub subdomain_settings: SubdomainSettings,
+    pub realm_cache: RealmCache,
+    pub csrf_trusted_origins: Vec<String>,
     pub avatar_settings: AvatarSettings,
+    pub server_version: ServerVersion,
+    pub notice_validator: NoticeValidator,
     pub shutdown_rx: shutdown::Receiver,
     pub db_pool: deadpool_postgres::Pool,
     pub queues: Mutex<Queues>,
-    pub rabbitmq_channel: lapin::Channel,
+    pub user_activity: UserActivity,
+    pub rabbitmq: Publisher,
+    pub notification_outbox: Outbox,
+    pub recent_notices: RecentNotices,
 }
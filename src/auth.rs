@@ -1,11 +1,13 @@
 use std::sync::Arc;
 
 use axum::extract::State;
+use axum::http::header;
 use axum::http::Request;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum_extra::extract::TypedHeader;
+use axum_extra::headers::authorization::{Authorization, Basic};
 use axum_extra::headers::Cookie;
 use constant_time_eq::constant_time_eq;
 use django_signing::Signer;
@@ -16,12 +18,17 @@ use sha2::{Digest, Sha256};
 
 use crate::app_error::AppError;
 use crate::app_state::AppState;
+use crate::csrf::check_csrf;
+use crate::response::{json_error, json_error_code, ErrorCode};
 use crate::types::{RealmId, UserId};
+use crate::user_agent::parse_client;
 
 #[derive(Clone, Debug)]
 pub struct AuthContext {
     pub user_id: UserId,
     pub realm_id: RealmId,
+    /// The client name, as determined by Zulip's `process_client`.
+    pub client_name: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -31,8 +38,37 @@ struct Session {
     _auth_user_hash: String,
 }
 
+fn request_host<B>(req: &Request<B>) -> &str {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn request_user_agent<B>(req: &Request<B>) -> Option<&str> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+}
+
+/// Whether the user's realm is the one the request's hostname refers to.
+async fn user_matches_subdomain(
+    state: &AppState,
+    host: &str,
+    realm_id: RealmId,
+) -> Result<bool, anyhow::Error> {
+    let subdomain = state.subdomain_settings.get_subdomain(host);
+    let host_realm_id = state
+        .realm_cache
+        .get_realm_id(&state.db_pool, &subdomain)
+        .await?;
+    Ok(host_realm_id == Some(realm_id))
+}
+
 async fn authenticate_django_session(
     state: &AppState,
+    host: &str,
+    client_name: String,
     cookie: Cookie,
 ) -> Result<Option<AuthContext>, anyhow::Error> {
     // Get the sessionid cookie
@@ -71,7 +107,12 @@ async fn authenticate_django_session(
     };
     let password: &str = user_row.get("password");
     let realm_id: RealmId = user_row.get("realm_id");
-    // TODO/boq: verify the realm against the request hostname
+
+    // Verify the realm against the request hostname
+    if !user_matches_subdomain(state, host, realm_id).await? {
+        tracing::debug!("User {user_id} is not in the realm for {host}");
+        return Ok(None);
+    }
 
     // Verify the session hash
     let mut key_hash = Sha256::new();
@@ -88,7 +129,11 @@ async fn authenticate_django_session(
     }
 
     // User is authenticated
-    Ok(Some(AuthContext { user_id, realm_id }))
+    Ok(Some(AuthContext {
+        user_id,
+        realm_id,
+        client_name,
+    }))
 }
 
 pub async fn django_session_auth<B>(
@@ -96,11 +141,28 @@ pub async fn django_session_auth<B>(
     TypedHeader(cookie): TypedHeader<Cookie>,
     mut req: Request<B>,
 ) -> Result<Request<B>, Result<Response, AppError>> {
-    // TODO/boq: CSRF protection
+    if let Err(reason) = check_csrf(
+        req.method(),
+        req.headers(),
+        Some(&cookie),
+        &state.csrf_trusted_origins,
+    ) {
+        tracing::debug!("CSRF failure: {reason}");
+        return Err(Ok((
+            StatusCode::FORBIDDEN,
+            json_error_code(format!("CSRF error: {reason}"), ErrorCode::CsrfFailed),
+        )
+            .into_response()));
+    }
 
-    if let Some(auth_context) = authenticate_django_session(&state, cookie)
-        .await
-        .map_err(|err| Err(err.into()))?
+    if let Some(auth_context) = authenticate_django_session(
+        &state,
+        request_host(&req),
+        parse_client(request_user_agent(&req), true),
+        cookie,
+    )
+    .await
+    .map_err(|err| Err(err.into()))?
     {
         req.extensions_mut().insert(auth_context);
         Ok(req)
@@ -109,11 +171,129 @@ pub async fn django_session_auth<B>(
     }
 }
 
+fn has_api_key_format(api_key: &str) -> bool {
+    api_key.len() == 32 && api_key.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+fn api_auth_error(message: &'static str, code: ErrorCode) -> Response {
+    (StatusCode::UNAUTHORIZED, json_error_code(message, code)).into_response()
+}
+
+async fn authenticate_api_key(
+    state: &AppState,
+    host: &str,
+    client_name: String,
+    role: &str,
+    api_key: &str,
+) -> Result<Result<AuthContext, Response>, anyhow::Error> {
+    // Remove whitespace to protect users from trivial errors.
+    let role = role.trim();
+    let api_key = api_key.trim();
+
+    if !has_api_key_format(api_key) {
+        return Ok(Err(api_auth_error(
+            "Malformed API key",
+            ErrorCode::InvalidApiKey,
+        )));
+    }
+
+    // Load the user from the database
+    let db = state.db_pool.get().await?;
+    let sql = "\
+SELECT
+    zerver_userprofile.id,
+    zerver_userprofile.realm_id,
+    zerver_userprofile.email,
+    zerver_userprofile.delivery_email,
+    zerver_userprofile.is_active,
+    zerver_realm.deactivated
+FROM zerver_userprofile
+JOIN zerver_realm ON zerver_realm.id = zerver_userprofile.realm_id
+WHERE zerver_userprofile.api_key = $1";
+    let Some(user_row) = db.query_opt(sql, &[&api_key]).await? else {
+        tracing::debug!("No user row");
+        return Ok(Err(api_auth_error(
+            "Invalid API key",
+            ErrorCode::InvalidApiKey,
+        )));
+    };
+    let user_id: UserId = user_row.get("id");
+    let realm_id: RealmId = user_row.get("realm_id");
+    let email: &str = user_row.get("email");
+    let delivery_email: &str = user_row.get("delivery_email");
+    let is_active: bool = user_row.get("is_active");
+    let realm_deactivated: bool = user_row.get("deactivated");
+
+    // This covers the case that the API key is correct, but for a different
+    // user.
+    if role.to_lowercase() != email.to_lowercase()
+        && role.to_lowercase() != delivery_email.to_lowercase()
+    {
+        tracing::debug!("API key is for a different user");
+        return Ok(Err(api_auth_error(
+            "Invalid API key",
+            ErrorCode::InvalidApiKey,
+        )));
+    }
+
+    if realm_deactivated {
+        return Ok(Err(api_auth_error(
+            "This organization has been deactivated",
+            ErrorCode::RealmDeactivated,
+        )));
+    }
+    if !is_active {
+        return Ok(Err(api_auth_error(
+            "Account is deactivated",
+            ErrorCode::UserDeactivated,
+        )));
+    }
+    if !user_matches_subdomain(state, host, realm_id).await? {
+        tracing::warn!("User {user_id} attempted to access API on wrong subdomain ({host})");
+        // Zulip's validate_account_and_subdomain raises a plain
+        // JsonableError here, not an authentication failure.
+        return Ok(Err((
+            StatusCode::BAD_REQUEST,
+            json_error("Account is not associated with this subdomain"),
+        )
+            .into_response()));
+    }
+
+    // User is authenticated
+    Ok(Ok(AuthContext {
+        user_id,
+        realm_id,
+        client_name,
+    }))
+}
+
 pub async fn api_auth<B>(
-    State(_state): State<Arc<AppState>>,
-    TypedHeader(_cookie): TypedHeader<Cookie>,
-    _req: Request<B>,
+    State(state): State<Arc<AppState>>,
+    authorization: Option<TypedHeader<Authorization<Basic>>>,
+    mut req: Request<B>,
 ) -> Result<Request<B>, Result<Response, AppError>> {
-    // TODO/boq: API authentication
-    Err(Ok(StatusCode::UNAUTHORIZED.into_response()))
+    let Some(TypedHeader(Authorization(credentials))) = authorization else {
+        return Err(Ok((
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, r#"Basic realm="zulip""#)],
+            json_error_code("Missing authorization header", ErrorCode::Unauthorized),
+        )
+            .into_response()));
+    };
+    match authenticate_api_key(
+        &state,
+        request_host(&req),
+        parse_client(request_user_agent(&req), false),
+        credentials.username(),
+        credentials.password(),
+    )
+    .await
+    .map_err(|err| Err(err.into()))?
+    {
+        Ok(auth_context) => {
+            req.extensions_mut().insert(auth_context);
+            Ok(req)
+        }
+        Err(response) => Err(Ok(response)),
+    }
 }
@@ -0,0 +1,332 @@
+//! A port of the parts of Django's `CsrfViewMiddleware` that apply to the
+//! requests we serve.
+
+use axum::http::{header, HeaderMap, Method};
+use axum_extra::headers::Cookie;
+use constant_time_eq::constant_time_eq;
+use std::borrow::Cow;
+
+const CSRF_COOKIE_NAME: &str = "csrftoken";
+const CSRF_HEADER_NAME: &str = "X-CSRFToken";
+
+const CSRF_SECRET_LENGTH: usize = 32;
+const CSRF_TOKEN_LENGTH: usize = 2 * CSRF_SECRET_LENGTH;
+const CSRF_ALLOWED_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+type Reason = Cow<'static, str>;
+
+/// Whether `host` matches `pattern`, where a pattern beginning with a period
+/// matches that domain and all of its subdomains.
+fn is_same_domain(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    if let Some(domain) = pattern.strip_prefix('.') {
+        host.ends_with(&pattern) || host == domain
+    } else {
+        host == pattern
+    }
+}
+
+/// Split a URL into its scheme and `host[:port]`, like Python's `urlsplit`.
+fn split_netloc(url: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let netloc = rest.split(['/', '?', '#']).next()?;
+    Some((scheme, netloc))
+}
+
+fn check_token_format(token: &str) -> Result<(), &'static str> {
+    if token.len() != CSRF_TOKEN_LENGTH && token.len() != CSRF_SECRET_LENGTH {
+        return Err("has incorrect length");
+    }
+    if !token.bytes().all(|b| CSRF_ALLOWED_CHARS.contains(&b)) {
+        return Err("has invalid characters");
+    }
+    Ok(())
+}
+
+/// Given a 64-character token that has passed [`check_token_format`], return
+/// the 32-character secret it was masked from.
+fn unmask_cipher_token(token: &str) -> Vec<u8> {
+    let index = |c| {
+        CSRF_ALLOWED_CHARS
+            .iter()
+            .position(|&allowed| allowed == c)
+            .unwrap()
+    };
+    let (mask, token) = token.as_bytes().split_at(CSRF_SECRET_LENGTH);
+    token
+        .iter()
+        .zip(mask)
+        .map(|(&x, &y)| {
+            CSRF_ALLOWED_CHARS
+                [(index(x) + CSRF_ALLOWED_CHARS.len() - index(y)) % CSRF_ALLOWED_CHARS.len()]
+        })
+        .collect()
+}
+
+fn unmask(token: &str) -> Cow<'_, [u8]> {
+    if token.len() == CSRF_TOKEN_LENGTH {
+        Cow::Owned(unmask_cipher_token(token))
+    } else {
+        Cow::Borrowed(token.as_bytes())
+    }
+}
+
+struct RequestOrigin<'a> {
+    secure: bool,
+    host: &'a str,
+}
+
+impl RequestOrigin<'_> {
+    fn scheme(&self) -> &'static str {
+        if self.secure {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
+fn origin_verified(
+    request: &RequestOrigin,
+    request_origin: &str,
+    trusted_origins: &[String],
+) -> bool {
+    if request_origin == format!("{}://{}", request.scheme(), request.host) {
+        return true;
+    }
+    if trusted_origins
+        .iter()
+        .any(|origin| !origin.contains('*') && origin == request_origin)
+    {
+        return true;
+    }
+    let Some((request_scheme, request_netloc)) = split_netloc(request_origin) else {
+        return false;
+    };
+    trusted_origins
+        .iter()
+        .filter(|origin| origin.contains('*'))
+        .filter_map(|origin| split_netloc(origin))
+        .any(|(scheme, host)| {
+            scheme == request_scheme && is_same_domain(request_netloc, host.trim_start_matches('*'))
+        })
+}
+
+fn check_referer(
+    request: &RequestOrigin,
+    referer: Option<&str>,
+    trusted_origins: &[String],
+) -> Result<(), Reason> {
+    let Some(referer) = referer else {
+        return Err("Referer checking failed - no Referer.".into());
+    };
+    let Some((referer_scheme, referer_netloc)) =
+        split_netloc(referer).filter(|(scheme, netloc)| !scheme.is_empty() && !netloc.is_empty())
+    else {
+        return Err("Referer checking failed - Referer is malformed.".into());
+    };
+    if referer_scheme != "https" {
+        return Err("Referer checking failed - Referer is insecure while host is secure.".into());
+    }
+    if trusted_origins
+        .iter()
+        .filter_map(|origin| split_netloc(origin))
+        .any(|(_, host)| is_same_domain(referer_netloc, host.trim_start_matches('*')))
+    {
+        return Ok(());
+    }
+    // Allow matching the current host:port exactly.
+    if is_same_domain(referer_netloc, request.host) {
+        return Ok(());
+    }
+    Err(format!("Referer checking failed - {referer} does not match any trusted origins.").into())
+}
+
+fn check_token(headers: &HeaderMap, cookie: Option<&Cookie>) -> Result<(), Reason> {
+    let Some(cookie_token) = cookie.and_then(|cookie| cookie.get(CSRF_COOKIE_NAME)) else {
+        return Err("CSRF cookie not set.".into());
+    };
+    check_token_format(cookie_token).map_err(|reason| format!("CSRF cookie {reason}."))?;
+    let csrf_secret = unmask(cookie_token);
+
+    // Django also accepts a csrfmiddlewaretoken POST parameter, but we only
+    // serve unsafe methods that don't carry form-encoded bodies from the web
+    // app, so only the header is checked.
+    let Some(request_csrf_token) = headers.get(CSRF_HEADER_NAME) else {
+        return Err("CSRF token missing.".into());
+    };
+    let request_csrf_token = request_csrf_token.to_str().unwrap_or_default();
+    check_token_format(request_csrf_token)
+        .map_err(|reason| format!("CSRF token from {CSRF_HEADER_NAME} {reason}."))?;
+    if !constant_time_eq(&unmask(request_csrf_token), &csrf_secret) {
+        return Err(format!("CSRF token from {CSRF_HEADER_NAME} incorrect.").into());
+    }
+    Ok(())
+}
+
+/// Check a cookie-authenticated request the way Django's `CsrfViewMiddleware`
+/// would, returning the reason it should be rejected, if any.
+pub fn check_csrf(
+    method: &Method,
+    headers: &HeaderMap,
+    cookie: Option<&Cookie>,
+    trusted_origins: &[String],
+) -> Result<(), Reason> {
+    if matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) {
+        return Ok(());
+    }
+
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let request = RequestOrigin {
+        // Zulip sets SECURE_PROXY_SSL_HEADER to trust this from the proxy.
+        secure: header(header::HeaderName::from_static("x-forwarded-proto")) == Some("https"),
+        host: header(header::HOST).unwrap_or_default(),
+    };
+
+    if let Some(request_origin) = header(header::ORIGIN) {
+        if !origin_verified(&request, request_origin, trusted_origins) {
+            return Err(format!(
+                "Origin checking failed - {request_origin} does not match any trusted origins."
+            )
+            .into());
+        }
+    } else if request.secure {
+        // If the Origin header wasn't provided, reject HTTPS requests if the
+        // Referer header doesn't match an allowed value.
+        check_referer(&request, header(header::REFERER), trusted_origins)?;
+    }
+
+    check_token(headers, cookie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_csrf, unmask_cipher_token, CSRF_ALLOWED_CHARS};
+    use axum::http::{HeaderMap, HeaderValue, Method};
+    use axum_extra::headers::{Cookie, Header};
+
+    const SECRET: &str = "abcdefghijklmnopqrstuvwxyzABCDEF";
+
+    /// Mask a secret the way Django's `_mask_cipher_secret` does.
+    fn mask(secret: &str, mask: &str) -> String {
+        let index = |c| CSRF_ALLOWED_CHARS.iter().position(|&a| a == c).unwrap();
+        let cipher: String = secret
+            .bytes()
+            .zip(mask.bytes())
+            .map(|(x, y)| {
+                CSRF_ALLOWED_CHARS[(index(x) + index(y)) % CSRF_ALLOWED_CHARS.len()] as char
+            })
+            .collect();
+        format!("{mask}{cipher}")
+    }
+
+    fn cookie(token: &str) -> Cookie {
+        let value = HeaderValue::from_str(&format!("csrftoken={token}")).unwrap();
+        Cookie::decode(&mut std::iter::once(&value)).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn unmasks_tokens() {
+        let token = mask(SECRET, "0123456789ZYXWVUTSRQPONMLKJIHGFE");
+        assert_eq!(unmask_cipher_token(&token), SECRET.as_bytes());
+    }
+
+    #[test]
+    fn checks_token() {
+        let token = mask(SECRET, "zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz");
+        let post = |headers: &HeaderMap, cookie: Option<&Cookie>| {
+            check_csrf(&Method::POST, headers, cookie, &[])
+        };
+        let with_token = headers(&[("host", "chat.example.com"), ("x-csrftoken", &token)]);
+
+        assert!(post(&with_token, Some(&cookie(SECRET))).is_ok());
+        assert!(post(
+            &with_token,
+            Some(&cookie(&mask(SECRET, &SECRET.to_uppercase())))
+        )
+        .is_ok());
+        assert_eq!(post(&with_token, None).unwrap_err(), "CSRF cookie not set.");
+        assert_eq!(
+            post(&with_token, Some(&cookie(&SECRET.to_uppercase()))).unwrap_err(),
+            "CSRF token from X-CSRFToken incorrect."
+        );
+        assert_eq!(
+            post(
+                &headers(&[("host", "chat.example.com")]),
+                Some(&cookie(SECRET))
+            )
+            .unwrap_err(),
+            "CSRF token missing."
+        );
+        assert_eq!(
+            post(
+                &headers(&[("host", "chat.example.com"), ("x-csrftoken", "short")]),
+                Some(&cookie(SECRET))
+            )
+            .unwrap_err(),
+            "CSRF token from X-CSRFToken has incorrect length."
+        );
+        // Safe methods aren't checked at all.
+        assert!(check_csrf(&Method::GET, &HeaderMap::new(), None, &[]).is_ok());
+    }
+
+    #[test]
+    fn checks_origin() {
+        let trusted = ["https://*.example.org".to_string()];
+        let check = |origin: &str| {
+            check_csrf(
+                &Method::DELETE,
+                &headers(&[
+                    ("host", "chat.example.com"),
+                    ("x-forwarded-proto", "https"),
+                    ("origin", origin),
+                    ("x-csrftoken", SECRET),
+                ]),
+                Some(&cookie(SECRET)),
+                &trusted,
+            )
+        };
+        assert!(check("https://chat.example.com").is_ok());
+        assert!(check("https://zulip.example.org").is_ok());
+        assert!(check("http://chat.example.com").is_err());
+        assert_eq!(
+            check("https://evil.example.net").unwrap_err(),
+            "Origin checking failed - https://evil.example.net does not match any trusted origins."
+        );
+    }
+
+    #[test]
+    fn checks_referer_without_origin() {
+        let check = |referer: Option<&str>| {
+            let mut headers = headers(&[
+                ("host", "chat.example.com"),
+                ("x-forwarded-proto", "https"),
+                ("x-csrftoken", SECRET),
+            ]);
+            if let Some(referer) = referer {
+                headers.insert("referer", HeaderValue::from_str(referer).unwrap());
+            }
+            check_csrf(&Method::POST, &headers, Some(&cookie(SECRET)), &[])
+        };
+        assert!(check(Some("https://chat.example.com/#narrow")).is_ok());
+        assert_eq!(
+            check(None).unwrap_err(),
+            "Referer checking failed - no Referer."
+        );
+        assert_eq!(
+            check(Some("http://chat.example.com/")).unwrap_err(),
+            "Referer checking failed - Referer is insecure while host is secure."
+        );
+        assert!(check(Some("https://evil.example.net/")).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::app_state::AppState;
+use crate::notice::missedmessage_hook;
+use crate::queues::EVENT_QUEUE_GC_FREQ;
+
+fn gc_event_queues(state: &Arc<AppState>) {
+    let start = Instant::now();
+    let mut queues = state.queues.lock().unwrap();
+    let removed = queues.gc(start);
+    for client in &removed {
+        if let Err(err) = missedmessage_hook(state, &queues, client) {
+            tracing::error!(
+                "missedmessage hook failed for queue {queue_id}: {err:#}",
+                queue_id = client.queue_id,
+            );
+        }
+    }
+    if !removed.is_empty() {
+        tracing::info!(
+            "removed {count} idle event queues in {elapsed:?}",
+            count = removed.len(),
+            elapsed = start.elapsed(),
+        );
+    }
+}
+
+/// Periodically garbage-collect event queues whose clients have not connected
+/// within their `queue_timeout`.
+pub async fn run(state: Arc<AppState>) {
+    let mut shutdown_rx = state.shutdown_rx.clone();
+    let mut interval = tokio::time::interval(EVENT_QUEUE_GC_FREQ);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => gc_event_queues(&state),
+            () = shutdown_rx.wait() => break,
+        }
+    }
+}
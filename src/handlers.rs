@@ -16,13 +16,25 @@ use std::sync::Arc;
 use crate::app_error::AppError;
 use crate::app_state::AppState;
 use crate::auth::AuthContext;
-use crate::narrow::Narrow;
-use crate::queues::{ClientEventEntry, ClientInfo, QueueId};
+use crate::narrow::{build_narrow, load_user_topics, needs_user_topics, RawNarrowTerm, UserTopics};
+use crate::notice::{missedmessage_hook, process_notice};
+use crate::outbox::OutboxMetrics;
+use crate::queues::{
+    ClientInfo, QueueId, WireClientEventEntry, DEFAULT_EVENT_QUEUE_TIMEOUT_SECS,
+    IDLE_EVENT_QUEUE_TIMEOUT_SECS, MAX_QUEUE_TIMEOUT_SECS,
+};
+use crate::rabbitmq::{ConnectionState, FailedNoticeMetrics};
 use crate::response::{json_error, json_error_code, json_success, ErrorCode};
+use crate::restart::send_web_reload_client_events;
 use crate::types::{RealmId, UserId};
+use crate::user_agent::is_pronouns_field_type_supported;
+use crate::validation::ValidationMetrics;
 
 type EventId = i64;
 
+/// Zulip's `UserProfile.ROLE_GUEST`.
+const ROLE_GUEST: i16 = 600;
+
 #[allow(dead_code, clippy::struct_excessive_bools)]
 #[serde_as]
 #[derive(Debug, Deserialize)]
@@ -52,7 +64,7 @@ pub struct GetEventsRequest {
     dont_block: bool,
     #[serde_as(as = "JsonString")]
     #[serde(default)]
-    narrow: Narrow,
+    narrow: Vec<RawNarrowTerm>,
     #[serde_as(as = "JsonString")]
     #[serde(default)]
     lifespan_secs: u32,
@@ -75,7 +87,7 @@ pub struct GetEventsRequest {
 
 #[derive(Serialize)]
 struct GetEventsResponse {
-    events: Vec<ClientEventEntry>,
+    events: Vec<WireClientEventEntry>,
     queue_id: QueueId,
 }
 
@@ -90,7 +102,7 @@ fn bad_queue_id(queue_id: &QueueId) -> Response {
         .into_response()
 }
 
-fn get_events_response(events: Vec<ClientEventEntry>, queue_id: QueueId) -> Response {
+fn get_events_response(events: Vec<WireClientEventEntry>, queue_id: QueueId) -> Response {
     (
         TypedHeader(headers::CacheControl::new().with_no_store().with_private()),
         json_success(GetEventsResponse { events, queue_id }),
@@ -98,6 +110,46 @@ fn get_events_response(events: Vec<ClientEventEntry>, queue_id: QueueId) -> Resp
         .into_response()
 }
 
+/// Like Zulip's `UserProfile.can_access_public_streams`: guests and users of
+/// Zephyr mirror realms can only see streams they're subscribed to.
+fn role_can_access_public_streams(role: i16, is_zephyr_mirror_realm: bool) -> bool {
+    role != ROLE_GUEST && !is_zephyr_mirror_realm
+}
+
+async fn can_access_public_streams(
+    state: &AppState,
+    user_profile_id: UserId,
+) -> Result<bool, anyhow::Error> {
+    let db = state.db_pool.get().await?;
+    let sql = "\
+SELECT zerver_userprofile.role, zerver_realm.is_zephyr_mirror_realm
+FROM zerver_userprofile
+JOIN zerver_realm ON zerver_realm.id = zerver_userprofile.realm_id
+WHERE zerver_userprofile.id = $1";
+    let Some(user_row) = db.query_opt(sql, &[&user_profile_id]).await? else {
+        return Ok(false);
+    };
+    let role: i16 = user_row.get("role");
+    let is_zephyr_mirror_realm: bool = user_row.get("is_zephyr_mirror_realm");
+    Ok(role_can_access_public_streams(role, is_zephyr_mirror_realm))
+}
+
+/// A client waiting on its queue, however the wait ends.
+struct LongPoll {
+    state: Arc<AppState>,
+    user_profile_id: UserId,
+    queue_id: QueueId,
+}
+
+impl Drop for LongPoll {
+    fn drop(&mut self) {
+        let mut queues = self.state.queues.lock().unwrap();
+        if let Some(client) = queues.by_id(self.user_profile_id, &self.queue_id) {
+            client.queue.disconnected();
+        }
+    }
+}
+
 async fn get_events_backend(
     state: Arc<AppState>,
     user_profile_id: UserId,
@@ -106,10 +158,28 @@ async fn get_events_backend(
 ) -> Result<Response, AppError> {
     tracing::debug!("get_events_backend user={user_profile_id:?} {args:?}");
 
-    if args.all_public_streams {
-        // TODO/boq: check if the user is allowed to access public streams
+    if args.all_public_streams && !can_access_public_streams(&state, user_profile_id).await? {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            json_error("User not authorized for this query"),
+        )
+            .into_response());
     }
 
+    let narrow = match build_narrow(args.narrow) {
+        Ok(narrow) => narrow,
+        Err(message) => return Ok((StatusCode::BAD_REQUEST, json_error(message)).into_response()),
+    };
+    let can_access_public_streams = args.all_public_streams
+        || (args.queue_id.is_none()
+            && !narrow.is_empty()
+            && can_access_public_streams(&state, user_profile_id).await?);
+    let user_topics = if args.queue_id.is_none() && needs_user_topics(&narrow) {
+        load_user_topics(&state.db_pool, user_profile_id).await?
+    } else {
+        UserTopics::default()
+    };
+
     let (queue_id, receiver) = {
         let mut queues = state.queues.lock().unwrap();
 
@@ -135,17 +205,27 @@ async fn get_events_backend(
                 user_profile_id,
                 realm_id,
                 event_types: args.event_types,
-                client_type_name: args.user_client.unwrap_or(Cow::Borrowed("unknown-client")), // TODO/boq: detect from User-Agent
+                client_type_name: args.user_client.unwrap_or(Cow::Borrowed("unknown-client")),
                 apply_markdown: args.apply_markdown,
                 client_gravatar: args.client_gravatar,
                 slim_presence: args.slim_presence,
                 all_public_streams: args.all_public_streams,
-                queue_timeout: args.lifespan_secs,
-                narrow: args.narrow,
+                can_access_public_streams,
+                // Default for lifespan_secs is DEFAULT_EVENT_QUEUE_TIMEOUT_SECS;
+                // but users can set it anywhere from IDLE_EVENT_QUEUE_TIMEOUT_SECS
+                // to MAX_QUEUE_TIMEOUT_SECS.
+                queue_timeout: if args.lifespan_secs == 0 {
+                    DEFAULT_EVENT_QUEUE_TIMEOUT_SECS
+                } else {
+                    args.lifespan_secs
+                }
+                .clamp(IDLE_EVENT_QUEUE_TIMEOUT_SECS, MAX_QUEUE_TIMEOUT_SECS),
+                narrow,
+                user_topics,
                 bulk_message_deletion: args.bulk_message_deletion,
                 stream_typing_notifications: args.stream_typing_notifications,
                 user_settings_object: args.user_settings_object,
-                pronouns_field_type_supported: args.pronouns_field_type_supported.unwrap_or(true), // TODO/boq: detect from User-Agent
+                pronouns_field_type_supported: args.pronouns_field_type_supported.unwrap_or(true),
                 linkifier_url_template: args.linkifier_url_template,
             };
             let queue_id = queues.register(info);
@@ -159,6 +239,13 @@ async fn get_events_backend(
         }
     };
 
+    // This future is dropped if the client goes away, so the guard is how we
+    // notice.
+    let _long_poll = LongPoll {
+        state: Arc::clone(&state),
+        user_profile_id,
+        queue_id,
+    };
     let mut shutdown_rx = state.shutdown_rx.clone();
     let events = tokio::select! {
         result = receiver => {
@@ -183,19 +270,63 @@ pub async fn get_root() -> &'static str {
     "boq 🤖\n"
 }
 
-/// Handle `POST /notify_tornado`.
-pub async fn post_notify_tornado() {
-    // TODO/boq: notify queues
+#[derive(Debug, Deserialize)]
+pub struct NotifyTornadoRequest {
+    secret: String,
+    data: String,
+}
+
+/// Handle `POST /notify_tornado` and `POST /api/internal/notify_tornado`.
+///
+/// This delivers a notice over HTTP, as if read from RabbitMQ.
+pub async fn post_notify_tornado(
+    State(state): State<Arc<AppState>>,
+    Form(NotifyTornadoRequest { secret, data }): Form<NotifyTornadoRequest>,
+) -> Result<Response, AppError> {
+    if !constant_time_eq(secret.as_bytes(), state.shared_secret.as_bytes()) {
+        return Ok((StatusCode::FORBIDDEN, json_error("Access denied")).into_response());
+    }
+
+    let notice = match serde_json::from_str(&data) {
+        Ok(notice) => notice,
+        Err(err) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                json_error(format!("Invalid 'data' argument: {err}")),
+            )
+                .into_response());
+        }
+    };
+    process_notice(&state, &mut state.queues.lock().unwrap(), notice)?;
+    Ok(json_success(()).into_response())
 }
 
 /// Handle `GET /json/events` and `GET /api/v1/events`.
 pub async fn get_events(
     State(state): State<Arc<AppState>>,
-    Extension(AuthContext { user_id, realm_id }): Extension<AuthContext>,
-    Form(args): Form<GetEventsRequest>,
+    Extension(AuthContext {
+        user_id,
+        realm_id,
+        client_name,
+    }): Extension<AuthContext>,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    Form(mut args): Form<GetEventsRequest>,
 ) -> impl IntoResponse {
     // TODO/boq: check user port
 
+    state
+        .user_activity
+        .record(user_id, &client_name, Cow::Borrowed("get_events"));
+
+    args.user_client.get_or_insert(Cow::Owned(client_name));
+    args.pronouns_field_type_supported.get_or_insert_with(|| {
+        is_pronouns_field_type_supported(
+            user_agent
+                .as_ref()
+                .map(|TypedHeader(user_agent)| user_agent.as_str()),
+        )
+    });
+
     get_events_backend(state, user_id, realm_id, args).await
 }
 
@@ -209,13 +340,20 @@ pub async fn delete_events(
     State(state): State<Arc<AppState>>,
     Extension(AuthContext { user_id, .. }): Extension<AuthContext>,
     Form(args): Form<DeleteEventsRequest>,
-) -> Response {
+) -> Result<Response, AppError> {
     let mut queues = state.queues.lock().unwrap();
-    if queues.delete(user_id, args.queue_id) {
-        json_success(()).into_response()
-    } else {
-        bad_queue_id(&args.queue_id)
+    let Some(client) = queues.delete(user_id, args.queue_id) else {
+        return Ok(bad_queue_id(&args.queue_id));
+    };
+    // The queue is gone either way, so a failed hook is ours to report, not
+    // the client's.
+    if let Err(err) = missedmessage_hook(&state, &queues, &client) {
+        tracing::error!(
+            "missedmessage hook failed for queue {queue_id}: {err:#}",
+            queue_id = client.queue_id,
+        );
     }
+    Ok(json_success(()).into_response())
 }
 
 #[derive(Debug, Deserialize)]
@@ -251,7 +389,100 @@ pub async fn post_events_internal(
 
     args.user_client.get_or_insert_with(|| "internal".into());
 
-    // TODO/boq: publish UserActivity record
+    state.user_activity.record(
+        user_profile_id,
+        "internal",
+        Cow::Borrowed("/api/v1/events/internal"),
+    );
 
     get_events_backend(state, user_profile_id, realm_id, args).await
 }
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct WebReloadClientsRequest {
+    secret: String,
+    #[serde_as(as = "JsonString")]
+    #[serde(default)]
+    client_count: Option<usize>,
+    #[serde_as(as = "JsonString")]
+    #[serde(default)]
+    immediate: bool,
+}
+
+#[derive(Serialize)]
+struct WebReloadClientsResponse {
+    sent_events: usize,
+    complete: bool,
+}
+
+/// Handle `POST /api/internal/web_reload_clients`.
+pub async fn post_web_reload_clients(
+    State(state): State<Arc<AppState>>,
+    Form(WebReloadClientsRequest {
+        secret,
+        client_count,
+        immediate,
+    }): Form<WebReloadClientsRequest>,
+) -> Response {
+    tracing::debug!("post_web_reload_clients client_count={client_count:?} immediate={immediate}");
+
+    if !constant_time_eq(secret.as_bytes(), state.shared_secret.as_bytes()) {
+        return (StatusCode::FORBIDDEN, json_error("Access denied")).into_response();
+    }
+
+    let mut queues = state.queues.lock().unwrap();
+    let sent_events = send_web_reload_client_events(&mut queues, immediate, client_count);
+    json_success(WebReloadClientsResponse {
+        sent_events,
+        complete: client_count.is_none_or(|count| sent_events < count),
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct RabbitMQStatusRequest {
+    secret: String,
+}
+
+#[derive(Serialize)]
+struct RabbitMQStatusResponse {
+    #[serde(flatten)]
+    connection: ConnectionState,
+    notification_outbox: OutboxMetrics,
+    notice_validation: ValidationMetrics,
+    failed_notices: FailedNoticeMetrics,
+}
+
+/// Handle `POST /api/internal/rabbitmq_status`.
+pub async fn post_rabbitmq_status(
+    State(state): State<Arc<AppState>>,
+    Form(RabbitMQStatusRequest { secret }): Form<RabbitMQStatusRequest>,
+) -> Response {
+    if !constant_time_eq(secret.as_bytes(), state.shared_secret.as_bytes()) {
+        return (StatusCode::FORBIDDEN, json_error("Access denied")).into_response();
+    }
+
+    json_success(RabbitMQStatusResponse {
+        connection: state.rabbitmq.connection_state(),
+        notification_outbox: state.notification_outbox.metrics(),
+        notice_validation: state.notice_validator.metrics(),
+        failed_notices: state.rabbitmq.failed_notice_metrics(),
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{role_can_access_public_streams, ROLE_GUEST};
+
+    /// Zulip's `UserProfile.ROLE_MEMBER`.
+    const ROLE_MEMBER: i16 = 400;
+
+    #[test]
+    fn guests_cannot_access_public_streams() {
+        assert!(role_can_access_public_streams(ROLE_MEMBER, false));
+        assert!(!role_can_access_public_streams(ROLE_GUEST, false));
+        assert!(!role_can_access_public_streams(ROLE_MEMBER, true));
+    }
+}
@@ -6,30 +6,53 @@ mod app_state;
 mod auth;
 mod avatar;
 mod avatar_hash;
+mod csrf;
 mod debug;
+mod event_types;
+mod gc;
 mod handlers;
 mod narrow;
 mod notice;
 mod notification_data;
+mod outbox;
+mod persist;
 mod queues;
 mod rabbitmq;
+mod realm;
+mod recent_notices;
 mod response;
+mod restart;
 mod secrets;
 mod shutdown;
+mod subdomain;
 mod types;
 mod upload;
+mod user_activity;
+mod user_agent;
+mod validation;
 
-use anyhow::{Context, Result};
+use amq_protocol_uri::{AMQPAuthority, AMQPQueryString, AMQPScheme, AMQPUri, AMQPUserInfo};
+use anyhow::{Context, Error, Result};
 use clap::Parser;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use url::Url;
 
 use crate::app_server::AppServer;
 use crate::app_state::AppState;
 use crate::avatar::AvatarSettings;
+use crate::outbox::Outbox;
 use crate::queues::Queues;
-use crate::rabbitmq::RabbitMQ;
+use crate::rabbitmq::{Publisher, RabbitMQ, RabbitMQSettings, TlsIdentity};
+use crate::realm::RealmCache;
+use crate::recent_notices::RecentNotices;
+use crate::restart::{send_restart_events, ServerVersion};
 use crate::secrets::Secrets;
+use crate::subdomain::SubdomainSettings;
+use crate::user_activity::UserActivity;
+use crate::validation::NoticeValidator;
 
 #[derive(Parser)]
 struct Cli {
@@ -38,15 +61,118 @@ struct Cli {
     #[arg(long)]
     secrets_file: String,
     #[arg(long)]
+    external_host: String,
+    #[arg(long = "realm-host", value_parser = parse_realm_host)]
+    realm_hosts: Vec<(String, String)>,
+    #[arg(long = "root-subdomain-alias", default_value = "www")]
+    root_subdomain_aliases: Vec<String>,
+    #[arg(long)]
+    csrf_trusted_origins: Vec<String>,
+    /// A full amqp:// or amqps:// URI, instead of the individual options.
+    /// Credentials missing from it are taken from --rabbitmq-user and the
+    /// secrets file.
+    #[arg(
+        long,
+        conflicts_with_all = ["rabbitmq_host", "rabbitmq_port", "rabbitmq_vhost", "rabbitmq_use_tls"]
+    )]
+    rabbitmq_uri: Option<String>,
+    #[arg(long, default_value = "localhost")]
     rabbitmq_host: String,
     #[arg(long)]
+    rabbitmq_port: Option<u16>,
+    #[arg(long, default_value = "/")]
+    rabbitmq_vhost: String,
+    #[arg(long)]
+    rabbitmq_use_tls: bool,
+    /// PEM file of extra CA certificates to trust for TLS connections.
+    #[arg(long)]
+    rabbitmq_tls_ca_file: Option<PathBuf>,
+    /// PKCS #12 file with a client certificate and key for TLS connections,
+    /// decrypted with rabbitmq_tls_client_identity_password from the secrets
+    /// file.
+    #[arg(long)]
+    rabbitmq_tls_client_identity_file: Option<PathBuf>,
+    /// Heartbeat timeout in seconds to negotiate with the server.
+    #[arg(long)]
+    rabbitmq_heartbeat: Option<u16>,
+    #[arg(long, default_value_t = 100)]
+    rabbitmq_prefetch_count: u16,
+    #[arg(long, default_value = "zulip")]
     rabbitmq_user: String,
     #[arg(long)]
     rabbitmq_notify_queue: String,
     #[arg(long)]
+    rabbitmq_dead_letter_queue: Option<String>,
+    #[arg(long)]
     enable_gravatar: bool,
     #[arg(long)]
     default_avatar_uri: String,
+    #[arg(long)]
+    persistent_queue_file: Option<PathBuf>,
+    #[arg(long)]
+    notification_outbox_file: Option<PathBuf>,
+    /// Zulip's `version.py`, for whichever of --zulip-version,
+    /// --zulip-merge-base and --zulip-feature-level aren't given.
+    #[arg(long)]
+    zulip_version_file: Option<PathBuf>,
+    #[arg(long)]
+    zulip_version: Option<String>,
+    #[arg(long)]
+    zulip_merge_base: Option<String>,
+    #[arg(long)]
+    zulip_feature_level: Option<i32>,
+    #[arg(long)]
+    immediate_restart: bool,
+    #[arg(long)]
+    strict_notice_validation: bool,
+}
+
+fn rabbitmq_uri(args: &Cli, password: String) -> Result<AMQPUri> {
+    let mut uri = if let Some(uri_str) = &args.rabbitmq_uri {
+        let url = Url::parse(uri_str).with_context(|| "invalid --rabbitmq-uri")?;
+        let mut uri: AMQPUri = uri_str.parse().map_err(Error::msg)?;
+        if url.username().is_empty() {
+            uri.authority
+                .userinfo
+                .username
+                .clone_from(&args.rabbitmq_user);
+        }
+        if url.password().is_none() {
+            uri.authority.userinfo.password = password;
+        }
+        uri
+    } else {
+        let scheme = if args.rabbitmq_use_tls {
+            AMQPScheme::AMQPS
+        } else {
+            AMQPScheme::AMQP
+        };
+        let port = args.rabbitmq_port.unwrap_or(scheme.default_port());
+        AMQPUri {
+            scheme,
+            authority: AMQPAuthority {
+                userinfo: AMQPUserInfo {
+                    username: args.rabbitmq_user.clone(),
+                    password,
+                },
+                host: args.rabbitmq_host.clone(),
+                port,
+            },
+            vhost: args.rabbitmq_vhost.clone(),
+            query: AMQPQueryString::default(),
+        }
+    };
+    if args.rabbitmq_heartbeat.is_some() {
+        uri.query.heartbeat = args.rabbitmq_heartbeat;
+    }
+    Ok(uri)
+}
+
+fn parse_realm_host(arg: &str) -> Result<(String, String)> {
+    let (subdomain, host) = arg
+        .split_once('=')
+        .with_context(|| "expected SUBDOMAIN=HOST")?;
+    Ok((subdomain.to_string(), host.to_string()))
 }
 
 #[tokio::main]
@@ -60,10 +186,11 @@ async fn main() -> Result<()> {
     let Secrets {
         local_database_password,
         rabbitmq_password,
+        rabbitmq_tls_client_identity_password,
         secret_key,
         shared_secret,
         avatar_salt,
-    } = Secrets::load(args.secrets_file).with_context(|| "failed to load secrets")?;
+    } = Secrets::load(&args.secrets_file).with_context(|| "failed to load secrets")?;
 
     // TODO/boq: support non-development database configuration
     let mut db_config = deadpool_postgres::Config::new();
@@ -76,43 +203,120 @@ async fn main() -> Result<()> {
         tokio_postgres::NoTls,
     )?;
 
-    let rabbitmq = RabbitMQ::connect(
-        args.rabbitmq_host,
-        args.rabbitmq_user,
-        rabbitmq_password,
-        &args.rabbitmq_notify_queue,
-    )
+    let tls_ca_certs = args
+        .rabbitmq_tls_ca_file
+        .as_ref()
+        .map(|path| fs::read_to_string(path).with_context(|| format!("failed to read {path:?}")))
+        .transpose()?;
+    let tls_client_identity = args
+        .rabbitmq_tls_client_identity_file
+        .as_ref()
+        .map(|path| -> Result<_> {
+            Ok(TlsIdentity {
+                der: fs::read(path).with_context(|| format!("failed to read {path:?}"))?,
+                password: rabbitmq_tls_client_identity_password.unwrap_or_default(),
+            })
+        })
+        .transpose()?;
+    let rabbitmq = RabbitMQ::connect(RabbitMQSettings {
+        uri: rabbitmq_uri(&args, rabbitmq_password)?,
+        tls_ca_certs,
+        tls_client_identity,
+        prefetch_count: args.rabbitmq_prefetch_count,
+        notify_queue: args.rabbitmq_notify_queue,
+        dead_letter_queue: args.rabbitmq_dead_letter_queue,
+    })
     .await
     .with_context(|| "failed to connect to RabbitMQ")?;
 
+    let mut queues = Queues::new();
+    if let Some(path) = &args.persistent_queue_file {
+        persist::load_event_queues(&mut queues, path);
+    }
+    let notification_outbox = Outbox::default();
+    if let Some(path) = &args.notification_outbox_file {
+        persist::load_notification_outbox(&notification_outbox, path);
+    }
+
+    let mut server_version = ServerVersion::new(
+        args.zulip_version,
+        args.zulip_merge_base,
+        args.zulip_feature_level,
+    );
+    if let Some(path) = &args.zulip_version_file {
+        server_version.fill_from_version_file(path)?;
+    }
+
     let state = Arc::new(AppState {
         shared_secret,
         secret_key,
+        subdomain_settings: SubdomainSettings {
+            external_host: args.external_host,
+            realm_hosts: args.realm_hosts.into_iter().collect(),
+            root_subdomain_aliases: args.root_subdomain_aliases,
+        },
+        realm_cache: RealmCache::default(),
+        csrf_trusted_origins: args.csrf_trusted_origins,
         avatar_settings: AvatarSettings {
             enable_gravatar: args.enable_gravatar,
             default_avatar_uri: args.default_avatar_uri,
             avatar_salt,
         },
+        server_version,
+        notice_validator: NoticeValidator::new(args.strict_notice_validation),
         shutdown_rx,
         db_pool,
-        queues: Mutex::new(Queues::new()),
-        rabbitmq_channel: rabbitmq.channel.clone(),
+        queues: Mutex::new(queues),
+        user_activity: UserActivity::default(),
+        rabbitmq: Publisher::new(rabbitmq.channel.clone()),
+        notification_outbox,
+        recent_notices: RecentNotices::default(),
     });
 
+    // Any queues at this point were restored from a previous run.
+    send_restart_events(
+        &mut state.queues.lock().unwrap(),
+        &state.server_version,
+        args.immediate_restart,
+    );
+
     let server = AppServer::new(&args.address, Arc::clone(&state))
         .await
         .with_context(|| "failed to start server")?;
 
-    let (rabbitmq_result, server_result) = tokio::join!(
-        tokio::spawn(shutdown_tx.on_error(rabbitmq.run(state))),
-        tokio::spawn(shutdown_tx.on_error(server.run()))
+    let (rabbitmq_result, server_result, gc_result, user_activity_result, outbox_result) = tokio::join!(
+        tokio::spawn(shutdown_tx.on_error(rabbitmq.run(Arc::clone(&state)))),
+        tokio::spawn(shutdown_tx.on_error(server.run())),
+        tokio::spawn(gc::run(Arc::clone(&state))),
+        tokio::spawn(user_activity::run(Arc::clone(&state))),
+        tokio::spawn(outbox::run(Arc::clone(&state))),
     );
+
+    // Failing to save one thing shouldn't stop us from saving the other.
+    let mut dump_failed = false;
+    if let Some(path) = &args.persistent_queue_file {
+        if let Err(err) = persist::dump_event_queues(&state.queues.lock().unwrap(), path) {
+            tracing::error!("failed to dump event queues: {err:#}");
+            dump_failed = true;
+        }
+    }
+    if let Some(path) = &args.notification_outbox_file {
+        if let Err(err) = persist::dump_notification_outbox(&state.notification_outbox, path) {
+            tracing::error!("failed to dump notification outbox: {err:#}");
+            dump_failed = true;
+        }
+    }
+
     rabbitmq_result
         .with_context(|| "RabbitMQ failed")?
         .with_context(|| "RabbitMQ failed")?;
     server_result
         .with_context(|| "server failed")?
         .with_context(|| "server failed")?;
+    gc_result.with_context(|| "garbage collector failed")?;
+    user_activity_result.with_context(|| "user activity publisher failed")?;
+    outbox_result.with_context(|| "notification outbox failed")?;
+    anyhow::ensure!(!dump_failed, "failed to save state at shutdown");
 
     tracing::info!("exited");
     Ok(())
@@ -1,49 +1,1171 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use crate::notice::{Message, MessageRecipient};
-use crate::types::MessageFlags;
+use crate::event_types::{SubscriptionEvent, TypedEvent, UserTopicEvent};
+use crate::notice::{Message, MessageRecipient, UserDisplayRecipient};
+use crate::types::{MessageFlags, MessageId, StreamId, UserId};
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum Operator {
-    Stream,
-    Topic,
-    Sender,
-    Is,
+const RESOLVED_TOPIC_PREFIX: &str = "✔ ";
+
+/// Zulip's `Recipient.STREAM`.
+const RECIPIENT_STREAM: i16 = 2;
+
+/// A narrow term as sent by clients, either in the legacy
+/// `[operator, operand]` form or in the
+/// `{"operator": ..., "operand": ..., "negated": ...}` form.
+///
+/// Terms are stored in the tuple form, with a negated term's operator
+/// prefixed by `-` as in Zulip's search syntax. Tornado rejects such terms,
+/// like any operator it doesn't support, so narrows are only written for
+/// Tornado through [`serialize_tornado_narrow`].
+#[derive(Clone, Debug)]
+pub struct RawNarrowTerm {
+    operator: String,
+    operand: Value,
+    negated: bool,
 }
 
-pub type Narrow = Vec<(Operator, String)>;
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawNarrowTermRepr {
+    Tuple(String, Value),
+    Dict {
+        operator: String,
+        operand: Value,
+        #[serde(default)]
+        negated: bool,
+    },
+}
 
-const RESOLVED_TOPIC_PREFIX: &str = "✔ ";
+impl<'de> Deserialize<'de> for RawNarrowTerm {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match RawNarrowTermRepr::deserialize(deserializer)? {
+            RawNarrowTermRepr::Tuple(operator, operand) => match operator.strip_prefix('-') {
+                Some(operator) => RawNarrowTerm {
+                    operator: operator.to_string(),
+                    operand,
+                    negated: true,
+                },
+                None => RawNarrowTerm {
+                    operator,
+                    operand,
+                    negated: false,
+                },
+            },
+            RawNarrowTermRepr::Dict {
+                operator,
+                operand,
+                negated,
+            } => RawNarrowTerm {
+                operator,
+                operand,
+                negated,
+            },
+        })
+    }
+}
+
+impl Serialize for RawNarrowTerm {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.negated {
+            (format!("-{}", self.operator), &self.operand).serialize(serializer)
+        } else {
+            (&self.operator, &self.operand).serialize(serializer)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum StreamOperand {
+    Id(StreamId),
+    Name(String),
+}
+
+#[derive(Clone, Debug)]
+enum UserOperand {
+    Id(UserId),
+    Email(String),
+}
+
+impl UserOperand {
+    fn matches(&self, user_id: UserId, email: &str) -> bool {
+        match self {
+            UserOperand::Id(id) => *id == user_id,
+            UserOperand::Email(operand) => *operand == email.to_lowercase(),
+        }
+    }
+
+    fn matches_recipient(&self, recipient: &UserDisplayRecipient) -> bool {
+        self.matches(recipient.id, &recipient.email)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum IsOperand {
+    Dm,
+    Starred,
+    Unread,
+    Mentioned,
+    Resolved,
+    Followed,
+    Muted,
+}
+
+#[derive(Clone, Debug)]
+enum Filter {
+    Channel(StreamOperand),
+    Topic(String),
+    Sender(UserOperand),
+    Dm(Vec<UserOperand>),
+    DmIncluding(UserOperand),
+    Id(MessageId),
+    Is(IsOperand),
+}
+
+fn parse_stream_operand(operand: &Value) -> Option<StreamOperand> {
+    match operand {
+        Value::Number(id) => Some(StreamOperand::Id(id.as_i64()?.try_into().ok()?)),
+        Value::String(name) => Some(StreamOperand::Name(name.to_lowercase())),
+        _ => None,
+    }
+}
+
+fn parse_user_operand(operand: &Value) -> Option<UserOperand> {
+    match operand {
+        Value::Number(id) => Some(UserOperand::Id(id.as_i64()?.try_into().ok()?)),
+        Value::String(email) => Some(UserOperand::Email(email.trim().to_lowercase())),
+        _ => None,
+    }
+}
+
+/// The `dm` operand is a list of user IDs or a comma-separated list of
+/// emails.
+fn parse_user_list_operand(operand: &Value) -> Option<Vec<UserOperand>> {
+    let users: Vec<UserOperand> = match operand {
+        Value::Array(ids) => ids
+            .iter()
+            .map(|id| Some(UserOperand::Id(id.as_i64()?.try_into().ok()?)))
+            .collect::<Option<_>>()?,
+        Value::String(emails) => emails
+            .split(',')
+            .map(str::trim)
+            .filter(|email| !email.is_empty())
+            .map(|email| UserOperand::Email(email.to_lowercase()))
+            .collect(),
+        Value::Number(_) => vec![parse_user_operand(operand)?],
+        _ => return None,
+    };
+    (!users.is_empty()).then_some(users)
+}
+
+fn parse_message_id_operand(operand: &Value) -> Option<MessageId> {
+    match operand {
+        Value::Number(id) => id.as_i64(),
+        Value::String(id) => id.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_is_operand(operand: &str) -> Option<IsOperand> {
+    Some(match operand {
+        "dm" | "private" => IsOperand::Dm,
+        "starred" => IsOperand::Starred,
+        "unread" => IsOperand::Unread,
+        "alerted" | "mentioned" => IsOperand::Mentioned,
+        "resolved" => IsOperand::Resolved,
+        "followed" => IsOperand::Followed,
+        "muted" => IsOperand::Muted,
+        _ => return None,
+    })
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "RawNarrowTerm", into = "RawNarrowTerm")]
+pub struct NarrowTerm {
+    raw: RawNarrowTerm,
+    filter: Filter,
+}
+
+impl TryFrom<RawNarrowTerm> for NarrowTerm {
+    type Error = String;
+
+    fn try_from(raw: RawNarrowTerm) -> Result<Self, Self::Error> {
+        let operator = raw.operator.as_str();
+        let operand = &raw.operand;
+        let filter = match operator {
+            "channel" | "stream" => parse_stream_operand(operand).map(Filter::Channel),
+            "topic" => operand
+                .as_str()
+                .map(|topic| Filter::Topic(topic.to_lowercase())),
+            "sender" => parse_user_operand(operand).map(Filter::Sender),
+            "dm" | "pm-with" => parse_user_list_operand(operand).map(Filter::Dm),
+            "dm-including" => parse_user_operand(operand).map(Filter::DmIncluding),
+            "id" => parse_message_id_operand(operand).map(Filter::Id),
+            "is" => match operand.as_str() {
+                Some(operand) => {
+                    Some(Filter::Is(parse_is_operand(operand).ok_or_else(|| {
+                        format!("Operand {operand} not supported.")
+                    })?))
+                }
+                None => None,
+            },
+            _ => return Err(format!("Operator {operator} not supported.")),
+        };
+        let filter = filter.ok_or_else(|| format!("Invalid operand for operator {operator}."))?;
+        Ok(NarrowTerm { raw, filter })
+    }
+}
+
+impl From<NarrowTerm> for RawNarrowTerm {
+    fn from(term: NarrowTerm) -> Self {
+        term.raw
+    }
+}
+
+impl NarrowTerm {
+    /// This term as Tornado's `build_narrow_predicate` evaluates it, if it
+    /// can: Tornado supports no negation, only stream names and sender
+    /// emails as operands, and only the oldest spellings of operators.
+    fn tornado_term(&self) -> Option<(&'static str, Value)> {
+        if self.raw.negated {
+            return None;
+        }
+        let operand = &self.raw.operand;
+        match &self.filter {
+            Filter::Channel(StreamOperand::Name(_)) => Some(("stream", operand.clone())),
+            Filter::Topic(_) => Some(("topic", operand.clone())),
+            Filter::Sender(UserOperand::Email(_)) => Some(("sender", operand.clone())),
+            Filter::Is(IsOperand::Dm) => Some(("is", "private".into())),
+            Filter::Is(
+                IsOperand::Starred | IsOperand::Unread | IsOperand::Mentioned | IsOperand::Resolved,
+            ) => Some(("is", operand.clone())),
+            _ => None,
+        }
+    }
+}
+
+pub type Narrow = Vec<NarrowTerm>;
+
+/// Whether Tornado can load and evaluate all of `narrow`.
+pub fn is_tornado_compatible(narrow: &Narrow) -> bool {
+    narrow.iter().all(|term| term.tornado_term().is_some())
+}
+
+/// Write the terms of `narrow` that Tornado can load, so that a rollback to
+/// Tornado keeps our event queues. Tornado discards every queue if it
+/// can't load one, so other terms are left out, which widens the narrow.
+pub fn serialize_tornado_narrow<S: Serializer>(
+    narrow: &Narrow,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(narrow.iter().filter_map(NarrowTerm::tornado_term))
+}
+
+/// Validate the narrow a client registered with, like Zulip's
+/// `check_narrow_for_events`.
+pub fn build_narrow(raw: Vec<RawNarrowTerm>) -> Result<Narrow, String> {
+    raw.into_iter().map(NarrowTerm::try_from).collect()
+}
+
+/// Whether matching `narrow` requires tracking the user's [`UserTopics`].
+pub fn needs_user_topics(narrow: &Narrow) -> bool {
+    narrow.iter().any(|term| {
+        matches!(
+            term.filter,
+            Filter::Is(IsOperand::Followed | IsOperand::Muted)
+        )
+    })
+}
+
+#[derive(Clone, Copy, Debug, Deserialize_repr, Eq, PartialEq, Serialize_repr)]
+#[repr(u8)]
+pub enum VisibilityPolicy {
+    Inherit = 0,
+    Muted = 1,
+    Unmuted = 2,
+    Followed = 3,
+}
+
+impl VisibilityPolicy {
+    fn from_i64(value: i64) -> Option<VisibilityPolicy> {
+        Some(match value {
+            0 => VisibilityPolicy::Inherit,
+            1 => VisibilityPolicy::Muted,
+            2 => VisibilityPolicy::Unmuted,
+            3 => VisibilityPolicy::Followed,
+            _ => return None,
+        })
+    }
+}
+
+/// The user's topic visibility policies and muted streams, which the
+/// `is:followed` and `is:muted` operators depend on. Tornado can't support
+/// these, since it has no way to know this state.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserTopics {
+    visibility_policies: HashMap<StreamId, HashMap<String, VisibilityPolicy>>,
+    muted_streams: HashSet<StreamId>,
+}
+
+fn get_stream_id(value: Option<&Value>) -> Option<StreamId> {
+    value?.as_i64()?.try_into().ok()
+}
+
+/// The `is_muted` property of a subscription, or the inverse of the legacy
+/// `in_home_view` property.
+fn get_is_muted(property: &str, value: &Value) -> Option<bool> {
+    let value = value.as_bool()?;
+    match property {
+        "is_muted" => Some(value),
+        "in_home_view" => Some(!value),
+        _ => None,
+    }
+}
+
+impl UserTopics {
+    pub fn is_empty(&self) -> bool {
+        self.visibility_policies.is_empty() && self.muted_streams.is_empty()
+    }
+
+    fn visibility_policy(&self, stream_id: StreamId, topic: &str) -> VisibilityPolicy {
+        self.visibility_policies
+            .get(&stream_id)
+            .and_then(|topics| topics.get(&topic.to_lowercase()))
+            .copied()
+            .unwrap_or(VisibilityPolicy::Inherit)
+    }
+
+    fn is_followed(&self, stream_id: StreamId, topic: &str) -> bool {
+        self.visibility_policy(stream_id, topic) == VisibilityPolicy::Followed
+    }
+
+    /// Muted topics, and topics in muted streams that haven't been unmuted or
+    /// followed.
+    fn is_muted(&self, stream_id: StreamId, topic: &str) -> bool {
+        match self.visibility_policy(stream_id, topic) {
+            VisibilityPolicy::Muted => true,
+            VisibilityPolicy::Unmuted | VisibilityPolicy::Followed => false,
+            VisibilityPolicy::Inherit => self.muted_streams.contains(&stream_id),
+        }
+    }
+
+    fn set_visibility_policy(
+        &mut self,
+        stream_id: StreamId,
+        topic: &str,
+        policy: VisibilityPolicy,
+    ) {
+        let topic = topic.to_lowercase();
+        if policy == VisibilityPolicy::Inherit {
+            if let Some(topics) = self.visibility_policies.get_mut(&stream_id) {
+                topics.remove(&topic);
+                if topics.is_empty() {
+                    self.visibility_policies.remove(&stream_id);
+                }
+            }
+        } else {
+            self.visibility_policies
+                .entry(stream_id)
+                .or_default()
+                .insert(topic, policy);
+        }
+    }
+
+    fn set_stream_muted(&mut self, stream_id: StreamId, muted: bool) {
+        if muted {
+            self.muted_streams.insert(stream_id);
+        } else {
+            self.muted_streams.remove(&stream_id);
+        }
+    }
+
+    /// Keep up to date with the `user_topic` and `subscription` events sent
+    /// to the user.
+    pub fn apply_event(&mut self, event: &TypedEvent) {
+        match event {
+            TypedEvent::UserTopic(UserTopicEvent {
+                stream_id,
+                topic_name,
+                visibility_policy,
+                ..
+            }) => self.set_visibility_policy(*stream_id, topic_name, *visibility_policy),
+            TypedEvent::Subscription(SubscriptionEvent::Update {
+                stream_id,
+                property,
+                value,
+                ..
+            }) => {
+                if let Some(muted) = get_is_muted(property, value) {
+                    self.set_stream_muted(*stream_id, muted);
+                }
+            }
+            TypedEvent::Subscription(SubscriptionEvent::Add { subscriptions, .. }) => {
+                for subscription in subscriptions {
+                    let muted = subscription
+                        .is_muted
+                        .or(subscription.in_home_view.map(|in_home_view| !in_home_view))
+                        .unwrap_or(false);
+                    self.set_stream_muted(subscription.stream_id, muted);
+                }
+            }
+            TypedEvent::Subscription(SubscriptionEvent::Remove { subscriptions, .. }) => {
+                for subscription in subscriptions {
+                    self.set_stream_muted(subscription.stream_id, false);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Load the user's [`UserTopics`] from the database.
+pub async fn load_user_topics(
+    db_pool: &deadpool_postgres::Pool,
+    user_profile_id: UserId,
+) -> Result<UserTopics, anyhow::Error> {
+    let db = db_pool.get().await?;
+    let mut user_topics = UserTopics::default();
+
+    let sql = "\
+SELECT stream_id, topic_name, visibility_policy
+FROM zerver_usertopic
+WHERE user_profile_id = $1";
+    for row in db.query(sql, &[&user_profile_id]).await? {
+        let visibility_policy: i16 = row.get("visibility_policy");
+        if let Some(policy) = VisibilityPolicy::from_i64(visibility_policy.into()) {
+            user_topics.set_visibility_policy(row.get("stream_id"), row.get("topic_name"), policy);
+        }
+    }
+
+    let sql = "\
+SELECT zerver_recipient.type_id
+FROM zerver_subscription
+JOIN zerver_recipient ON zerver_recipient.id = zerver_subscription.recipient_id
+WHERE zerver_subscription.user_profile_id = $1
+AND zerver_subscription.active
+AND zerver_subscription.is_muted
+AND zerver_recipient.type = $2";
+    for row in db
+        .query(sql, &[&user_profile_id, &RECIPIENT_STREAM])
+        .await?
+    {
+        user_topics.muted_streams.insert(row.get("type_id"));
+    }
+
+    Ok(user_topics)
+}
+
+fn message_stream_id(message: &Message) -> Option<StreamId> {
+    get_stream_id(message.attrs.get("stream_id"))
+}
 
-pub fn matches_narrow(message: &Message, flags: &MessageFlags, narrow: &Narrow) -> bool {
-    // TODO: Eventually handle negated narrow terms.
-    narrow.iter().all(|(operator, operand)| match operator {
-        Operator::Stream => match &message.recipient {
+fn satisfies_filter(
+    filter: &Filter,
+    message: &Message,
+    flags: &MessageFlags,
+    user_profile_id: UserId,
+    user_topics: &UserTopics,
+) -> bool {
+    match (filter, &message.recipient) {
+        (Filter::Channel(StreamOperand::Id(id)), MessageRecipient::Stream { .. }) => {
+            message_stream_id(message) == Some(*id)
+        }
+        (
+            Filter::Channel(StreamOperand::Name(name)),
             MessageRecipient::Stream {
                 display_recipient, ..
-            } => operand.to_lowercase() == display_recipient.to_lowercase(),
-            MessageRecipient::Private { .. } => false,
-        },
-        Operator::Topic => match &message.recipient {
-            MessageRecipient::Stream { subject, .. } => {
-                operand.to_lowercase() == subject.to_lowercase()
+            },
+        ) => *name == display_recipient.to_lowercase(),
+        (Filter::Topic(topic), MessageRecipient::Stream { subject, .. }) => {
+            *topic == subject.to_lowercase()
+        }
+        (Filter::Channel(_) | Filter::Topic(_), MessageRecipient::Private { .. }) => false,
+        (Filter::Sender(sender), _) => sender.matches(message.sender_id, &message.sender_email),
+        // The conversation with exactly these users, plus the current user.
+        (Filter::Dm(users), MessageRecipient::Private { display_recipient }) => {
+            users.iter().all(|user| {
+                display_recipient
+                    .iter()
+                    .any(|recipient| user.matches_recipient(recipient))
+            }) && display_recipient.iter().all(|recipient| {
+                recipient.id == user_profile_id
+                    || users.iter().any(|user| user.matches_recipient(recipient))
+            })
+        }
+        (Filter::DmIncluding(user), MessageRecipient::Private { display_recipient }) => {
+            display_recipient
+                .iter()
+                .any(|recipient| user.matches_recipient(recipient))
+        }
+        (Filter::Dm(_) | Filter::DmIncluding(_), MessageRecipient::Stream { .. }) => false,
+        (Filter::Id(id), _) => message.id == *id,
+        (Filter::Is(IsOperand::Dm), recipient) => {
+            matches!(recipient, MessageRecipient::Private { .. })
+        }
+        (Filter::Is(IsOperand::Starred), _) => flags.contains("starred"),
+        (Filter::Is(IsOperand::Unread), _) => !flags.contains("read"),
+        (Filter::Is(IsOperand::Mentioned), _) => flags.contains("mentioned"),
+        (Filter::Is(IsOperand::Resolved), MessageRecipient::Stream { subject, .. }) => {
+            subject.starts_with(RESOLVED_TOPIC_PREFIX)
+        }
+        (Filter::Is(IsOperand::Followed), MessageRecipient::Stream { subject, .. }) => {
+            message_stream_id(message)
+                .is_some_and(|stream_id| user_topics.is_followed(stream_id, subject))
+        }
+        (Filter::Is(IsOperand::Muted), MessageRecipient::Stream { subject, .. }) => {
+            message_stream_id(message)
+                .is_some_and(|stream_id| user_topics.is_muted(stream_id, subject))
+        }
+        (
+            Filter::Is(IsOperand::Resolved | IsOperand::Followed | IsOperand::Muted),
+            MessageRecipient::Private { .. },
+        ) => false,
+    }
+}
+
+pub fn matches_narrow(
+    message: &Message,
+    flags: &MessageFlags,
+    narrow: &Narrow,
+    user_profile_id: UserId,
+    user_topics: &UserTopics,
+) -> bool {
+    narrow.iter().all(|term| {
+        satisfies_filter(&term.filter, message, flags, user_profile_id, user_topics)
+            != term.raw.negated
+    })
+}
+
+/// How many messages' locations to remember.
+const MESSAGE_LOCATIONS_CAPACITY: usize = 10_000;
+
+/// Where a message is, as last recorded from a `message` or `update_message`
+/// event.
+#[derive(Clone, Debug)]
+pub enum StoredLocation {
+    Private,
+    Stream {
+        stream_id: StreamId,
+        /// Unknown after a move, since the event only names the old stream.
+        stream_name: Option<String>,
+        topic: String,
+    },
+}
+
+/// The locations of recently sent messages, for applying narrows to events
+/// that don't say where their messages are, such as rendering-only edits.
+#[derive(Default)]
+pub struct MessageLocations {
+    locations: HashMap<MessageId, StoredLocation>,
+    order: VecDeque<MessageId>,
+}
+
+impl MessageLocations {
+    pub fn get(&self, message_id: MessageId) -> Option<&StoredLocation> {
+        self.locations.get(&message_id)
+    }
+
+    fn insert(&mut self, message_id: MessageId, location: StoredLocation) {
+        if self.locations.insert(message_id, location).is_some() {
+            return;
+        }
+        self.order.push_back(message_id);
+        if self.order.len() > MESSAGE_LOCATIONS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.locations.remove(&oldest);
             }
-            MessageRecipient::Private { .. } => false,
-        },
-        Operator::Sender => operand.to_lowercase() == message.sender_email.to_lowercase(),
-        Operator::Is => match operand.as_str() {
-            "dm" | "private" => matches!(message.recipient, MessageRecipient::Private { .. }),
-            "starred" => flags.contains("starred"),
-            "unread" => !flags.contains("read"),
-            "alerted" | "mentioned" => flags.contains("mentioned"),
-            "resolved" => match &message.recipient {
-                MessageRecipient::Stream { subject, .. } => {
-                    subject.starts_with(RESOLVED_TOPIC_PREFIX)
+        }
+    }
+
+    /// Record where a newly sent message is.
+    pub fn record_message(
+        &mut self,
+        message_id: MessageId,
+        recipient: &MessageRecipient,
+        attrs: &HashMap<String, Value>,
+    ) {
+        let location = match recipient {
+            MessageRecipient::Private { .. } => StoredLocation::Private,
+            MessageRecipient::Stream {
+                display_recipient,
+                subject,
+            } => {
+                let Some(stream_id) = get_stream_id(attrs.get("stream_id")) else {
+                    return;
+                };
+                StoredLocation::Stream {
+                    stream_id,
+                    stream_name: Some(display_recipient.clone()),
+                    topic: subject.clone(),
                 }
-                MessageRecipient::Private { .. } => false,
+            }
+        };
+        self.insert(message_id, location);
+    }
+
+    /// Follow the messages an `update_message` event moves.
+    pub fn record_update(
+        &mut self,
+        attrs: &HashMap<String, Value>,
+        stream_name: Option<&str>,
+        message_id: MessageId,
+    ) {
+        // Only edits of stream messages carry the stream, and only moves
+        // carry the new stream or topic.
+        let Some(stream_id) = get_stream_id(attrs.get("stream_id")) else {
+            return;
+        };
+        let new_stream_id = get_stream_id(attrs.get("new_stream_id"));
+        let new_topic = get_str(attrs, "subject").or_else(|| get_str(attrs, "topic"));
+        if new_stream_id.is_none() && new_topic.is_none() {
+            return;
+        }
+
+        for message_id in update_message_ids(attrs, message_id) {
+            let topic = match (new_topic, self.locations.get(&message_id)) {
+                (Some(topic), _) => topic.to_string(),
+                (None, Some(StoredLocation::Stream { topic, .. })) => topic.clone(),
+                (None, _) => continue,
+            };
+            self.insert(
+                message_id,
+                StoredLocation::Stream {
+                    stream_id: new_stream_id.unwrap_or(stream_id),
+                    stream_name: match new_stream_id {
+                        Some(_) => None,
+                        None => stream_name.map(str::to_string),
+                    },
+                    topic,
+                },
+            );
+        }
+    }
+}
+
+/// Where the messages affected by an `update_message` or `delete_message`
+/// event are, as far as the event tells us; `None` means unknown.
+#[derive(Clone, Copy, Debug, Default)]
+struct MessageLocation<'a> {
+    is_private: Option<bool>,
+    stream_id: Option<StreamId>,
+    stream_name: Option<&'a str>,
+    topic: Option<&'a str>,
+}
+
+impl<'a> MessageLocation<'a> {
+    /// Fill in what the event doesn't tell us from where we last saw the
+    /// message.
+    fn or_stored(self, stored: Option<&'a StoredLocation>) -> Self {
+        match stored {
+            None => self,
+            Some(StoredLocation::Private) => MessageLocation {
+                is_private: self.is_private.or(Some(true)),
+                ..self
+            },
+            Some(StoredLocation::Stream {
+                stream_id,
+                stream_name,
+                topic,
+            }) => MessageLocation {
+                is_private: self.is_private.or(Some(false)),
+                stream_id: self.stream_id.or(Some(*stream_id)),
+                stream_name: self.stream_name.or(stream_name.as_deref()),
+                topic: self.topic.or(Some(topic)),
             },
-            _ => true,
+        }
+    }
+}
+
+/// What an `update_message` or `delete_message` event tells us about its
+/// messages, beyond their location.
+struct EventMessages<'a> {
+    message_ids: &'a [MessageId],
+    flags: Option<&'a MessageFlags>,
+}
+
+/// Like [`satisfies_filter`], but returns `None` if the event doesn't carry
+/// enough information to decide. A stream or topic term never matches
+/// messages whose stream or topic we don't know, so that narrowed clients
+/// only get events we know to be about messages in their narrow.
+fn location_satisfies_filter(
+    filter: &Filter,
+    location: &MessageLocation,
+    messages: &EventMessages,
+    user_topics: &UserTopics,
+) -> Option<bool> {
+    let stream_topic = location.stream_id.zip(location.topic);
+    match filter {
+        Filter::Channel(StreamOperand::Id(id)) => Some(location.stream_id == Some(*id)),
+        Filter::Channel(StreamOperand::Name(name)) => Some(
+            location
+                .stream_name
+                .is_some_and(|stream_name| *name == stream_name.to_lowercase()),
+        ),
+        Filter::Topic(topic) => Some(
+            location
+                .topic
+                .is_some_and(|location_topic| *topic == location_topic.to_lowercase()),
+        ),
+        Filter::Sender(_) => None,
+        Filter::Dm(_) | Filter::DmIncluding(_) => match location.is_private? {
+            true => None,
+            false => Some(false),
         },
+        Filter::Id(id) => {
+            (!messages.message_ids.is_empty()).then(|| messages.message_ids.contains(id))
+        }
+        Filter::Is(IsOperand::Dm) => location.is_private,
+        Filter::Is(IsOperand::Starred) => Some(messages.flags?.contains("starred")),
+        Filter::Is(IsOperand::Unread) => Some(!messages.flags?.contains("read")),
+        Filter::Is(IsOperand::Mentioned) => Some(messages.flags?.contains("mentioned")),
+        Filter::Is(IsOperand::Resolved) => Some(
+            location
+                .topic
+                .is_some_and(|topic| topic.starts_with(RESOLVED_TOPIC_PREFIX)),
+        ),
+        Filter::Is(IsOperand::Followed) => Some(
+            stream_topic
+                .is_some_and(|(stream_id, topic)| user_topics.is_followed(stream_id, topic)),
+        ),
+        Filter::Is(IsOperand::Muted) => Some(
+            stream_topic.is_some_and(|(stream_id, topic)| user_topics.is_muted(stream_id, topic)),
+        ),
+    }
+}
+
+/// Whether the messages at `location` might be in the narrow: terms we
+/// can't decide from the event are assumed to match.
+fn location_matches_narrow(
+    location: &MessageLocation,
+    messages: &EventMessages,
+    narrow: &Narrow,
+    user_topics: &UserTopics,
+) -> bool {
+    narrow.iter().all(|term| {
+        location_satisfies_filter(&term.filter, location, messages, user_topics)
+            .is_none_or(|satisfied| satisfied != term.raw.negated)
     })
 }
+
+fn get_str<'a>(attrs: &'a HashMap<String, Value>, key: &str) -> Option<&'a str> {
+    attrs.get(key).and_then(Value::as_str)
+}
+
+/// The messages an `update_message` event is about.
+fn update_message_ids(attrs: &HashMap<String, Value>, message_id: MessageId) -> Vec<MessageId> {
+    let mut message_ids: Vec<MessageId> = attrs
+        .get("message_ids")
+        .and_then(Value::as_array)
+        .map(|ids| ids.iter().filter_map(Value::as_i64).collect())
+        .unwrap_or_default();
+    if !message_ids.contains(&message_id) {
+        message_ids.push(message_id);
+    }
+    message_ids
+}
+
+/// Whether an `update_message` event is for messages in the narrow, either
+/// before or after a move.
+pub fn update_message_matches_narrow(
+    attrs: &HashMap<String, Value>,
+    stream_name: Option<&str>,
+    message_id: MessageId,
+    flags: &MessageFlags,
+    stored_location: Option<&StoredLocation>,
+    narrow: &Narrow,
+    user_topics: &UserTopics,
+) -> bool {
+    if narrow.is_empty() {
+        return true;
+    }
+
+    let message_ids = update_message_ids(attrs, message_id);
+    let messages = EventMessages {
+        message_ids: &message_ids,
+        flags: Some(flags),
+    };
+
+    let stream_id = get_stream_id(attrs.get("stream_id"));
+    let orig_topic = get_str(attrs, "orig_subject");
+    let new_topic = get_str(attrs, "subject").or_else(|| get_str(attrs, "topic"));
+    let new_stream_id = get_stream_id(attrs.get("new_stream_id"));
+    // Only edits of stream messages carry the stream.
+    let is_private = stream_id.is_some().then_some(false);
+
+    let before = MessageLocation {
+        is_private,
+        stream_id,
+        stream_name,
+        topic: orig_topic.or(new_topic),
+    }
+    .or_stored(stored_location);
+    let mut after = MessageLocation {
+        is_private,
+        stream_id: new_stream_id.or(stream_id),
+        stream_name,
+        topic: new_topic.or(orig_topic),
+    }
+    .or_stored(stored_location);
+    if new_stream_id.is_some() {
+        // We only know the name of the stream the messages were moved from.
+        after.stream_name = None;
+    }
+    location_matches_narrow(&before, &messages, narrow, user_topics)
+        || location_matches_narrow(&after, &messages, narrow, user_topics)
+}
+
+/// Whether a `delete_message` event is for messages in the narrow.
+pub fn delete_message_matches_narrow(
+    attrs: &HashMap<String, Value>,
+    message_ids: &[MessageId],
+    stored_location: Option<&StoredLocation>,
+    narrow: &Narrow,
+    user_topics: &UserTopics,
+) -> bool {
+    if narrow.is_empty() {
+        return true;
+    }
+
+    let location = MessageLocation {
+        is_private: get_str(attrs, "message_type").map(|message_type| message_type == "private"),
+        stream_id: get_stream_id(attrs.get("stream_id")),
+        stream_name: None,
+        topic: get_str(attrs, "topic"),
+    }
+    .or_stored(stored_location);
+    let messages = EventMessages {
+        message_ids,
+        flags: None,
+    };
+    location_matches_narrow(&location, &messages, narrow, user_topics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_narrow, delete_message_matches_narrow, matches_narrow, update_message_matches_narrow,
+        MessageLocations, Narrow, StoredLocation, UserTopics, VisibilityPolicy,
+    };
+    use crate::notice::{Message, MessageRecipient};
+    use crate::types::{MessageFlags, StreamId};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn narrow(terms: Value) -> Narrow {
+        build_narrow(serde_json::from_value(terms).unwrap()).unwrap()
+    }
+
+    fn attrs(attrs: Value) -> HashMap<String, Value> {
+        serde_json::from_value(attrs).unwrap()
+    }
+
+    fn stream_location(stream_id: StreamId, topic: &str) -> StoredLocation {
+        StoredLocation::Stream {
+            stream_id,
+            stream_name: Some("Denmark".to_string()),
+            topic: topic.to_string(),
+        }
+    }
+
+    fn update_matches(
+        event: Value,
+        stream_name: Option<&str>,
+        stored: Option<&StoredLocation>,
+        narrow: &Narrow,
+    ) -> bool {
+        update_message_matches_narrow(
+            &attrs(event),
+            stream_name,
+            1,
+            &MessageFlags::new(),
+            stored,
+            narrow,
+            &UserTopics::default(),
+        )
+    }
+
+    #[test]
+    fn update_message_in_stream_narrow() {
+        let narrow = narrow(json!([["stream", 5]]));
+        let edit = json!({"stream_id": 5, "content": "x"});
+        assert!(update_matches(edit, Some("Denmark"), None, &narrow));
+        let edit = json!({"stream_id": 6, "content": "x"});
+        assert!(!update_matches(edit, Some("Verona"), None, &narrow));
+
+        // A direct message edit carries no stream.
+        let edit = json!({"content": "x"});
+        assert!(!update_matches(edit, None, None, &narrow));
+        let edit = json!({"content": "x"});
+        assert!(!update_matches(
+            edit,
+            None,
+            Some(&StoredLocation::Private),
+            &narrow
+        ));
+    }
+
+    #[test]
+    fn rendering_only_edit_uses_stored_location() {
+        let narrow = narrow(json!([
+            {"operator": "stream", "operand": 5},
+            {"operator": "topic", "operand": "Hello"},
+        ]));
+        let edit = json!({"rendering_only": true, "rendered_content": "<p>x</p>"});
+        assert!(update_matches(
+            edit.clone(),
+            None,
+            Some(&stream_location(5, "hello")),
+            &narrow
+        ));
+        assert!(!update_matches(
+            edit.clone(),
+            None,
+            Some(&stream_location(5, "other")),
+            &narrow
+        ));
+        assert!(!update_matches(edit, None, None, &narrow));
+    }
+
+    #[test]
+    fn moves_match_either_location() {
+        let narrow = narrow(json!([["stream", 7]]));
+        let moved = json!({"stream_id": 5, "new_stream_id": 7, "propagate_mode": "change_all"});
+        assert!(update_matches(
+            moved,
+            Some("Denmark"),
+            Some(&stream_location(5, "hello")),
+            &narrow
+        ));
+
+        let narrow = self::narrow(json!([["stream", "Denmark"]]));
+        let moved = json!({"stream_id": 5, "new_stream_id": 7});
+        assert!(update_matches(moved, Some("Denmark"), None, &narrow));
+        let moved = json!({"stream_id": 7, "new_stream_id": 5});
+        assert!(!update_matches(moved, Some("Verona"), None, &narrow));
+    }
+
+    #[test]
+    fn negated_stream_narrow_gets_direct_messages() {
+        let narrow = narrow(json!([{"operator": "stream", "operand": 5, "negated": true}]));
+        let edit = json!({"content": "x"});
+        assert!(update_matches(edit, None, None, &narrow));
+    }
+
+    #[test]
+    fn delete_message_in_narrow() {
+        let narrow = narrow(json!([["stream", "denmark"], ["topic", "hello"]]));
+        let delete = |event: Value, stored: Option<&StoredLocation>| {
+            delete_message_matches_narrow(
+                &attrs(event),
+                &[1, 2],
+                stored,
+                &narrow,
+                &UserTopics::default(),
+            )
+        };
+        let event = json!({"message_type": "stream", "stream_id": 5, "topic": "Hello"});
+        assert!(delete(event.clone(), Some(&stream_location(5, "Hello"))));
+        // Deletions don't name the stream, so we need to have seen it.
+        assert!(!delete(event, None));
+        let event = json!({"message_type": "private"});
+        assert!(!delete(event, None));
+
+        let narrow = self::narrow(json!([["is", "dm"]]));
+        let event = json!({"message_type": "private"});
+        assert!(delete_message_matches_narrow(
+            &attrs(event),
+            &[1],
+            None,
+            &narrow,
+            &UserTopics::default(),
+        ));
+    }
+
+    #[test]
+    fn message_locations_follow_moves() {
+        let mut locations = MessageLocations::default();
+        let recipient = MessageRecipient::Stream {
+            display_recipient: "Denmark".to_string(),
+            subject: "hello".to_string(),
+        };
+        locations.record_message(1, &recipient, &attrs(json!({"stream_id": 5})));
+        locations.record_message(2, &recipient, &attrs(json!({"stream_id": 5})));
+
+        let moved = attrs(json!({
+            "stream_id": 5,
+            "orig_subject": "hello",
+            "subject": "bye",
+            "message_ids": [1, 2],
+        }));
+        locations.record_update(&moved, Some("Denmark"), 1);
+        let moved = attrs(json!({"stream_id": 5, "new_stream_id": 7, "message_ids": [2]}));
+        locations.record_update(&moved, Some("Denmark"), 2);
+
+        assert!(matches!(
+            locations.get(1),
+            Some(StoredLocation::Stream { stream_id: 5, stream_name: Some(name), topic })
+                if name == "Denmark" && topic == "bye"
+        ));
+        assert!(matches!(
+            locations.get(2),
+            Some(StoredLocation::Stream { stream_id: 7, stream_name: None, topic })
+                if topic == "bye"
+        ));
+    }
+
+    #[test]
+    fn negated_terms_round_trip_as_tuples() {
+        let terms = json!([
+            ["stream", "Denmark"],
+            {"operator": "topic", "operand": "hello", "negated": true},
+            {"operator": "is", "operand": "dm", "negated": false},
+        ]);
+        let narrow = narrow(terms);
+        let stored = serde_json::to_value(&narrow).unwrap();
+        assert_eq!(
+            stored,
+            json!([["stream", "Denmark"], ["-topic", "hello"], ["is", "dm"]])
+        );
+        let reloaded: Narrow = serde_json::from_value(stored.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&reloaded).unwrap(), stored);
+        assert!(reloaded[1].raw.negated && reloaded[1].raw.operator == "topic");
+    }
+
+    fn stream_message(topic: &str) -> Message {
+        serde_json::from_value(json!({
+            "id": 42,
+            "sender_id": 10,
+            "sender_email": "iago@zulip.com",
+            "client": "website",
+            "avatar_url": null,
+            "content_type": "text/html",
+            "content": "<p>hi</p>",
+            "type": "stream",
+            "display_recipient": "Denmark",
+            "subject": topic,
+            "stream_id": 5,
+        }))
+        .unwrap()
+    }
+
+    fn direct_message() -> Message {
+        let user = |id, email| json!({"id": id, "email": email, "full_name": email, "is_mirror_dummy": false});
+        serde_json::from_value(json!({
+            "id": 43,
+            "sender_id": 10,
+            "sender_email": "iago@zulip.com",
+            "client": "website",
+            "avatar_url": null,
+            "content_type": "text/html",
+            "content": "<p>hi</p>",
+            "type": "private",
+            "display_recipient": [user(10, "iago@zulip.com"), user(11, "hamlet@zulip.com")],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn message_narrows() {
+        let stream = stream_message("Hello");
+        let direct = direct_message();
+        let flags = ["starred".to_string()].into_iter().collect();
+        let user_topics = UserTopics::default();
+        let matches = |message: &Message, terms: Value| {
+            matches_narrow(message, &flags, &narrow(terms), 11, &user_topics)
+        };
+
+        assert!(matches(&stream, json!([["stream", 5], ["topic", "hello"]])));
+        assert!(matches(&stream, json!([["channel", "denmark"]])));
+        assert!(!matches(&stream, json!([["stream", 6]])));
+        assert!(matches(&stream, json!([["sender", "IAGO@zulip.com"]])));
+        assert!(matches(&stream, json!([["sender", 10]])));
+        assert!(matches(&stream, json!([["id", "42"]])));
+        assert!(matches(&stream, json!([["is", "starred"]])));
+        assert!(matches(&stream, json!([["is", "unread"]])));
+        assert!(!matches(&stream, json!([["is", "dm"]])));
+        assert!(!matches(&stream, json!([["is", "resolved"]])));
+        assert!(matches(
+            &stream_message("✔ Hello"),
+            json!([["is", "resolved"]])
+        ));
+
+        assert!(matches(&direct, json!([["is", "dm"]])));
+        assert!(matches(&direct, json!([["dm", [10]]])));
+        assert!(matches(&direct, json!([["pm-with", "iago@zulip.com"]])));
+        assert!(!matches(&direct, json!([["dm", [12]]])));
+        assert!(matches(&direct, json!([["dm-including", 11]])));
+        assert!(!matches(&direct, json!([["stream", 5]])));
+        assert!(!matches(&stream, json!([["dm-including", 11]])));
+    }
+
+    #[test]
+    fn negated_message_narrows() {
+        let message = stream_message("Hello");
+        let flags = Default::default();
+        let user_topics = UserTopics::default();
+        let matches =
+            |terms: Value| matches_narrow(&message, &flags, &narrow(terms), 11, &user_topics);
+
+        assert!(!matches(
+            json!([{"operator": "topic", "operand": "hello", "negated": true}])
+        ));
+        assert!(matches(
+            json!([{"operator": "topic", "operand": "bye", "negated": true}])
+        ));
+        assert!(matches(json!([
+            ["stream", 5],
+            {"operator": "is", "operand": "dm", "negated": true},
+        ])));
+        assert!(matches(json!([["-is", "starred"]])));
+    }
+
+    #[test]
+    fn followed_and_muted_topics() {
+        let message = stream_message("Hello");
+        let flags = Default::default();
+        let mut user_topics = UserTopics::default();
+        user_topics.set_visibility_policy(5, "hello", VisibilityPolicy::Followed);
+        assert!(matches_narrow(
+            &message,
+            &flags,
+            &narrow(json!([["is", "followed"]])),
+            11,
+            &user_topics
+        ));
+        assert!(!matches_narrow(
+            &message,
+            &flags,
+            &narrow(json!([["is", "muted"]])),
+            11,
+            &user_topics
+        ));
+
+        user_topics.set_visibility_policy(5, "Hello", VisibilityPolicy::Muted);
+        assert!(matches_narrow(
+            &message,
+            &flags,
+            &narrow(json!([["is", "muted"]])),
+            11,
+            &user_topics
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_terms() {
+        let build = |terms: Value| build_narrow(serde_json::from_value(terms).unwrap());
+        assert_eq!(
+            build(json!([["search", "foo"]])).unwrap_err(),
+            "Operator search not supported."
+        );
+        assert_eq!(
+            build(json!([["is", "alerted_and_starred"]])).unwrap_err(),
+            "Operand alerted_and_starred not supported."
+        );
+        assert_eq!(
+            build(json!([["stream", true]])).unwrap_err(),
+            "Invalid operand for operator stream."
+        );
+    }
+}
@@ -1,19 +1,22 @@
 use anyhow::Result;
-use lapin::options::BasicPublishOptions;
-use lapin::BasicProperties;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::value::RawValue;
 use serde_json::Value;
 
 use crate::app_state::AppState;
 use crate::avatar::{get_avatar_field, AvatarSettings, AvatarSource};
+use crate::event_types::{Linkifier, RealmLinkifiersEvent, TypedEvent};
+use crate::narrow::StoredLocation;
 use crate::notification_data::{NotificationTrigger, UserIdSets, UserMessageNotificationsData};
 use crate::queues::{Client, QueueId, Queues};
 use crate::types::{MessageFlags, MessageId, RealmId, UserGroupId, UserId};
+use crate::validation::{
+    json_diff, unexpected_event_fields, unexpected_fields, MESSAGE_DICT_FIELDS,
+};
 
 #[derive(Debug, Deserialize)]
 struct MessageUser {
@@ -91,9 +94,21 @@ pub enum MessageRecipient {
     },
 }
 
+/// A flattened map alongside a flattened [`MessageRecipient`] also sees the
+/// recipient's keys; drop them so they aren't serialized twice.
+fn deserialize_message_attrs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Value>, D::Error> {
+    let mut attrs = HashMap::<String, Value>::deserialize(deserializer)?;
+    for key in ["type", "display_recipient", "subject", "topic"] {
+        attrs.remove(key);
+    }
+    Ok(attrs)
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct WideMessage {
-    #[serde(flatten)]
+    #[serde(flatten, deserialize_with = "deserialize_message_attrs")]
     attrs: HashMap<String, Value>,
     sender_email: String,
     sender_delivery_email: Option<String>,
@@ -119,9 +134,9 @@ pub struct WideMessage {
     invite_only_stream: (),
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Message {
-    #[serde(flatten)]
+    #[serde(flatten, deserialize_with = "deserialize_message_attrs")]
     pub attrs: HashMap<String, Value>,
     pub sender_email: String,
     pub sender_id: UserId,
@@ -129,12 +144,12 @@ pub struct Message {
     pub client: String,
     pub avatar_url: Option<String>,
     pub content_type: ContentType,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub rendered_content: Option<String>,
     pub content: String,
     #[serde(flatten)]
     pub recipient: MessageRecipient,
-    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub invite_only_stream: bool,
 }
 
@@ -246,7 +261,7 @@ pub struct MessageEvent {
     message_dict: WideMessage,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MessageUserInternalData {
     #[serde(flatten)]
     user_notifications_data: UserMessageNotificationsData,
@@ -267,11 +282,11 @@ fn is_false(b: &bool) -> bool {
     !b
 }
 
-#[derive(Clone, Debug, Default, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 struct Notified {
-    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default, skip_serializing_if = "is_false")]
     push_notified: bool,
-    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default, skip_serializing_if = "is_false")]
     email_notified: bool,
 }
 
@@ -316,20 +331,9 @@ fn maybe_enqueue_notifications(
                 trigger,
                 mentioned_user_group_id,
             });
-            let payload = serde_json::to_vec(&notice)?;
-            let state = Arc::clone(state);
-            tokio::spawn(async move {
-                state
-                    .rabbitmq_channel
-                    .basic_publish(
-                        "",
-                        "missedmessage_mobile_notifications",
-                        BasicPublishOptions::default(),
-                        &payload,
-                        BasicProperties::default().with_delivery_mode(2),
-                    )
-                    .await
-            });
+            state
+                .notification_outbox
+                .enqueue("missedmessage_mobile_notifications", &notice)?;
             notified.push_notified = true;
         }
     }
@@ -347,20 +351,9 @@ fn maybe_enqueue_notifications(
                 trigger,
                 mentioned_user_group_id,
             };
-            let payload = serde_json::to_vec(&notice)?;
-            let state = Arc::clone(state);
-            tokio::spawn(async move {
-                state
-                    .rabbitmq_channel
-                    .basic_publish(
-                        "",
-                        "missedmessage_emails",
-                        BasicPublishOptions::default(),
-                        &payload,
-                        BasicProperties::default().with_delivery_mode(2),
-                    )
-                    .await
-            });
+            state
+                .notification_outbox
+                .enqueue("missedmessage_emails", &notice)?;
             notified.email_notified = true;
         }
     }
@@ -368,45 +361,51 @@ fn maybe_enqueue_notifications(
     Ok(notified)
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum SpecialClientEvent {
     Message {
         message: Arc<Message>,
         flags: MessageFlags,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         internal_data: Option<MessageUserInternalData>,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         local_message_id: Option<String>,
     },
     UpdateMessage {
         #[serde(flatten)]
         attrs: Arc<HashMap<String, Value>>,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         stream_name: Option<String>,
         message_id: MessageId,
         // TODO/compatibility: Make this required when one can no longer directly
         // update from 4.x to main.
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         rendering_only: Option<bool>,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         user_id: Option<UserId>,
         flags: MessageFlags,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         mentioned_user_group_id: Option<UserId>,
+        /// Only needed to decide which clients get the event.
+        #[serde(skip)]
+        stored_location: Option<Arc<StoredLocation>>,
     },
     DeleteMessage {
         #[serde(flatten)]
         attrs: Arc<HashMap<String, Value>>,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         message_ids: Option<Arc<[MessageId]>>,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         message_id: Option<MessageId>,
+        /// Only needed to decide which clients get the event.
+        #[serde(skip)]
+        stored_location: Option<Arc<StoredLocation>>,
     },
     Presence {
         #[serde(flatten)]
         slim_presence: Arc<SlimPresence>,
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         email: Option<String>,
     },
     CustomProfileFields {
@@ -414,7 +413,7 @@ pub enum SpecialClientEvent {
     },
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum ClientEvent {
     Special(SpecialClientEvent),
@@ -482,6 +481,7 @@ fn enqueue_message_to_client(
 /// for high-level documentation on this subsystem.
 fn process_message_event(
     state: &Arc<AppState>,
+    queues: &mut Queues,
     mut event_template: MessageEvent,
     users: MessageUsers,
 ) -> Result<()> {
@@ -545,6 +545,11 @@ fn process_message_event(
 
     let sender_id = wide_message.sender_id;
     let message_id = wide_message.id;
+    queues.message_locations.record_message(
+        message_id,
+        &wide_message.recipient,
+        &wide_message.attrs,
+    );
 
     let user_id_sets = UserIdSets {
         private_message: matches!(wide_message.recipient, MessageRecipient::Private { .. }),
@@ -567,7 +572,6 @@ fn process_message_event(
     };
 
     let mut flavor_cache = HashMap::new();
-    let mut queues = state.queues.lock().unwrap();
 
     let processed_user_ids: HashSet<UserId> = users
         .into_iter()
@@ -590,7 +594,7 @@ fn process_message_event(
             // case we can avoid the more expensive `receiver_is_off_zulip` call,
             // and move on to process the next user.
             let notified = if user_notifications_data.is_notifiable(sender_id, true) {
-                let idle = receiver_is_off_zulip(&queues, user_profile_id)
+                let idle = receiver_is_off_zulip(queues, user_profile_id)
                     || presence_idle_user_ids.contains(&user_profile_id);
                 maybe_enqueue_notifications(
                     state,
@@ -638,29 +642,120 @@ fn process_message_event(
 
     if event_template.stream_name.is_some() && !event_template.invite_only {
         if let Some(realm_id) = event_template.realm_id {
-            if let Some(client_keys) = queues.for_realm_all_streams(realm_id) {
-                for client_key in client_keys.clone() {
-                    let client = queues.get_mut(client_key);
+            enqueue_message_to_public_stream_clients(
+                queues,
+                realm_id,
+                &wide_message,
+                &mut flavor_cache,
+                &processed_user_ids,
+                event_template.sender_queue_id,
+                event_template.local_id.as_ref(),
+                &state.avatar_settings,
+            );
+        }
+    }
 
-                    if processed_user_ids.contains(&client.info().user_profile_id) {
-                        continue;
-                    }
+    Ok(())
+}
 
-                    let is_sender = Some(client.queue_id) == event_template.sender_queue_id;
-                    enqueue_message_to_client(
-                        &wide_message,
-                        &mut flavor_cache,
-                        client,
-                        &MessageFlags::new(),
-                        is_sender,
-                        None,
-                        event_template.invite_only,
-                        event_template.local_id.as_ref(),
-                        &state.avatar_settings,
-                    );
-                }
-            }
+/// Deliver a public stream message to the realm's clients that see messages
+/// beyond their own, other than those of the users it was sent to.
+#[allow(clippy::too_many_arguments)]
+fn enqueue_message_to_public_stream_clients(
+    queues: &mut Queues,
+    realm_id: RealmId,
+    wide_message: &WideMessage,
+    flavor_cache: &mut HashMap<MessageFlavor, Arc<Message>>,
+    processed_user_ids: &HashSet<UserId>,
+    sender_queue_id: Option<QueueId>,
+    local_id: Option<&String>,
+    avatar_settings: &AvatarSettings,
+) {
+    let Some(client_keys) = queues.for_realm_all_streams(realm_id) else {
+        return;
+    };
+    for client_key in client_keys.clone() {
+        let client = queues.get_mut(client_key);
+
+        if processed_user_ids.contains(&client.info().user_profile_id) {
+            continue;
         }
+
+        let is_sender = Some(client.queue_id) == sender_queue_id;
+        enqueue_message_to_client(
+            wide_message,
+            flavor_cache,
+            client,
+            &MessageFlags::new(),
+            is_sender,
+            None,
+            false,
+            local_id,
+            avatar_settings,
+        );
+    }
+}
+
+/// The `receiver_is_off_zulip` logic used to determine whether a user has no
+/// active client suffers from a somewhat fundamental race condition. If the
+/// client is no longer on the Internet, `receiver_is_off_zulip` will still
+/// return false for `DEFAULT_EVENT_QUEUE_TIMEOUT_SECS`, until the queue is
+/// garbage-collected. This would cause us to reliably miss push/email
+/// notifying users for messages arriving during the
+/// `DEFAULT_EVENT_QUEUE_TIMEOUT_SECS` after they suspend their laptop (for
+/// example). We address this by, when the queue is garbage-collected at the
+/// end of those 10 minutes, checking to see if it's the last one, and if so,
+/// potentially triggering notifications to the user at that time, resulting
+/// in at most a `DEFAULT_EVENT_QUEUE_TIMEOUT_SECS` delay in the arrival of
+/// their notifications.
+///
+/// As Zulip's APIs get more popular and the mobile apps start using
+/// long-lived event queues for perf optimization, future versions of this
+/// will likely need to replace checking `last_for_client` with something more
+/// complicated, so that we only consider clients like web browsers, not the
+/// mobile apps or random API scripts.
+///
+/// `client` must already have been removed from `queues`.
+pub fn missedmessage_hook(state: &Arc<AppState>, queues: &Queues, client: &Client) -> Result<()> {
+    // Only process missedmessage hook when the last queue for a client has
+    // been garbage collected
+    let last_for_client = queues.for_user(client.info().user_profile_id).is_none();
+    if !last_for_client {
+        return Ok(());
+    }
+
+    for event in client.queue.contents() {
+        let ClientEvent::Special(SpecialClientEvent::Message {
+            message,
+            internal_data: Some(internal_data),
+            ..
+        }) = event
+        else {
+            continue;
+        };
+
+        // Since we just GC'd the last event queue, the user is definitely
+        // idle.
+        let idle = true;
+
+        // The user ID isn't stored in the internal data, since it's the
+        // client's.
+        let user_notifications_data = UserMessageNotificationsData {
+            user_id: client.info().user_profile_id,
+            ..internal_data.user_notifications_data.clone()
+        };
+
+        // Pass on the information on whether a push or email notification was
+        // already sent.
+        maybe_enqueue_notifications(
+            state,
+            &user_notifications_data,
+            message.sender_id,
+            message.id,
+            internal_data.mentioned_user_group_id,
+            idle,
+            &internal_data.notified,
+        )?;
     }
 
     Ok(())
@@ -793,6 +888,7 @@ fn maybe_enqueue_notifications_for_message_update(
 
 fn process_update_message_event(
     state: &Arc<AppState>,
+    queues: &mut Queues,
     event_template: UpdateMessageEvent,
     users: Vec<MessageUser>,
 ) -> Result<()> {
@@ -821,6 +917,16 @@ fn process_update_message_event(
 
     let stream_name = event_template.stream_name;
     let message_id = event_template.message_id;
+    let stored_location = queues
+        .message_locations
+        .get(message_id)
+        .cloned()
+        .map(Arc::new);
+    queues.message_locations.record_update(
+        &event_template.attrs,
+        stream_name.as_deref(),
+        message_id,
+    );
 
     // TODO/compatibility: Modern `update_message` events contain the
     // rendering_only key, which indicates whether the update is a link preview
@@ -833,8 +939,6 @@ fn process_update_message_event(
         .rendering_only
         .unwrap_or_else(|| event_template.user_id.is_none());
 
-    let mut queues = state.queues.lock().unwrap();
-
     for user_data in users {
         let user_profile_id = user_data.id;
 
@@ -858,7 +962,7 @@ fn process_update_message_event(
 
             maybe_enqueue_notifications_for_message_update(
                 state,
-                &queues,
+                queues,
                 &user_notifications_data,
                 message_id,
                 acting_user_id,
@@ -883,6 +987,7 @@ fn process_update_message_event(
                     user_id: event_template.user_id,
                     flags: user_data.flags.clone(),
                     mentioned_user_group_id: user_data.mentioned_user_group_id,
+                    stored_location: stored_location.clone(),
                 });
                 if client.accepts_event(&user_event) {
                     client.add_event(user_event);
@@ -915,7 +1020,7 @@ enum DeleteMessageUsers {
 }
 
 fn process_delete_message_event(
-    state: &AppState,
+    queues: &mut Queues,
     event: DeleteMessageEvent,
     users: DeleteMessageUsers,
 ) {
@@ -926,16 +1031,21 @@ fn process_delete_message_event(
         }
     };
 
+    let stored_location = event
+        .message_ids
+        .first()
+        .and_then(|&message_id| queues.message_locations.get(message_id))
+        .cloned()
+        .map(Arc::new);
     let user_event = ClientEvent::Special(SpecialClientEvent::DeleteMessage {
         attrs: Arc::clone(&event.attrs),
         message_ids: Some(Arc::clone(&event.message_ids)),
         message_id: None,
+        stored_location: stored_location.clone(),
     });
 
     tracing::debug!("processing delete_message event {event:?} {user_ids:?}");
 
-    let mut queues = state.queues.lock().unwrap();
-
     for user_profile_id in user_ids {
         if let Some(client_keys) = queues.for_user(user_profile_id) {
             for client_key in client_keys.clone() {
@@ -960,8 +1070,11 @@ fn process_delete_message_event(
                             attrs: Arc::clone(&event.attrs),
                             message_ids: None,
                             message_id: Some(message_id),
+                            stored_location: stored_location.clone(),
                         });
-                    client.add_event(compatibility_event);
+                    if client.accepts_event(&compatibility_event) {
+                        client.add_event(compatibility_event);
+                    }
                 }
             }
         }
@@ -983,7 +1096,7 @@ pub struct PresenceEvent {
     email: String,
 }
 
-fn process_presence_event(state: &AppState, event: PresenceEvent, user_ids: Vec<UserId>) {
+fn process_presence_event(queues: &mut Queues, event: PresenceEvent, user_ids: Vec<UserId>) {
     tracing::debug!("processing presence event {event:?} {user_ids:?}");
 
     if event.slim_presence.user_id.is_none() {
@@ -998,8 +1111,6 @@ fn process_presence_event(state: &AppState, event: PresenceEvent, user_ids: Vec<
         email: None,
     });
 
-    let mut queues = state.queues.lock().unwrap();
-
     for user_profile_id in user_ids {
         if let Some(client_keys) = queues.for_user(user_profile_id) {
             for client_key in client_keys.clone() {
@@ -1051,7 +1162,7 @@ pub struct CustomProfileFieldsEvent {
 }
 
 fn process_custom_profile_fields_event(
-    state: &AppState,
+    queues: &mut Queues,
     event: CustomProfileFieldsEvent,
     user_ids: Vec<UserId>,
 ) {
@@ -1074,8 +1185,6 @@ fn process_custom_profile_fields_event(
         fields: event.fields,
     });
 
-    let mut queues = state.queues.lock().unwrap();
-
     for user_profile_id in user_ids {
         if let Some(client_keys) = queues.for_user(user_profile_id) {
             for client_key in client_keys.clone() {
@@ -1102,15 +1211,28 @@ pub struct CleanupQueueEvent {
 }
 
 /// This event may be generated to forward cleanup requests to the right shard.
-fn process_cleanup_queue_event(state: &AppState, event: CleanupQueueEvent, (user_id,): (UserId,)) {
+fn process_cleanup_queue_event(
+    state: &Arc<AppState>,
+    queues: &mut Queues,
+    event: CleanupQueueEvent,
+    (user_id,): (UserId,),
+) -> Result<()> {
     tracing::debug!("processing cleanup_queue event {event:?} {user_id:?}");
-    let mut queues = state.queues.lock().unwrap();
-    if !queues.delete(user_id, event.queue_id) {
+    if let Some(client) = queues.delete(user_id, event.queue_id) {
+        // Failing the notice wouldn't bring the queue back.
+        if let Err(err) = missedmessage_hook(state, queues, &client) {
+            tracing::error!(
+                "missedmessage hook failed for queue {queue_id}: {err:#}",
+                queue_id = client.queue_id,
+            );
+        }
+    } else {
         tracing::info!(
             "Ignoring cleanup request for bad queue id {queue_id} ({user_id})",
             queue_id = event.queue_id,
         );
     }
+    Ok(())
 }
 
 #[derive(Debug, Deserialize)]
@@ -1120,26 +1242,124 @@ pub struct OtherEvent {
     attrs: Arc<HashMap<String, Value>>,
 }
 
-fn process_other_event(state: &AppState, event: OtherEvent, user_ids: Vec<UserId>) {
-    tracing::debug!("processing event {event:?} {user_ids:?}");
+#[derive(Debug, Serialize)]
+struct LegacyLinkifier {
+    pattern: String,
+    url_format: String,
+    id: i32,
+}
+
+/// Convert a linkifier's RFC 6570 `url_template` to the `url_format` that
+/// clients without the `linkifier_url_template` capability expect. Only
+/// templates using simple `{var}` expansions can be converted.
+fn url_template_to_url_format(url_template: &str) -> Option<String> {
+    let mut url_format = String::new();
+    let mut rest = url_template;
+    while let Some(index) = rest.find(['{', '}', '%']) {
+        url_format.push_str(&rest[..index]);
+        if rest[index..].starts_with('%') {
+            url_format.push_str("%%");
+            rest = &rest[index + 1..];
+        } else if rest[index..].starts_with('{') {
+            let (name, after) = rest[index + 1..].split_once('}')?;
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+                return None;
+            }
+            url_format.push_str("%(");
+            url_format.push_str(name);
+            url_format.push_str(")s");
+            rest = after;
+        } else {
+            return None;
+        }
+    }
+    url_format.push_str(rest);
+    Some(url_format)
+}
+
+/// The `realm_linkifiers` event for clients without the
+/// `linkifier_url_template` capability, dropping any linkifiers that can't be
+/// expressed with a `url_format`.
+fn legacy_realm_linkifiers_event(
+    attrs: &HashMap<String, Value>,
+    linkifiers: &[Linkifier],
+) -> ClientEvent {
+    let legacy_linkifiers: Vec<LegacyLinkifier> = linkifiers
+        .iter()
+        .filter_map(|linkifier| {
+            Some(LegacyLinkifier {
+                url_format: url_template_to_url_format(&linkifier.url_template)?,
+                pattern: linkifier.pattern.clone(),
+                id: linkifier.id,
+            })
+        })
+        .collect();
+
+    let mut legacy_attrs = attrs.clone();
+    legacy_attrs.insert(
+        "realm_linkifiers".to_string(),
+        serde_json::to_value(legacy_linkifiers).unwrap(),
+    );
+    ClientEvent::Other {
+        r#type: "realm_linkifiers".to_string(),
+        attrs: Arc::new(legacy_attrs),
+    }
+}
+
+/// Parse an event that isn't processed specially into its typed model.
+/// Types we don't model are `TypedEvent::Unknown`, and so is an event of a
+/// modeled type that doesn't match its model, after a warning, so that it's
+/// still delivered as Django sent it.
+fn parse_typed_event(event_type: &str, raw_event: &RawValue) -> TypedEvent {
+    serde_json::from_str(raw_event.get()).unwrap_or_else(|err| {
+        tracing::warn!("failed to parse {event_type} event, delivering it untyped: {err}");
+        TypedEvent::Unknown
+    })
+}
+
+fn process_other_event(
+    queues: &mut Queues,
+    raw_event: &RawValue,
+    user_ids: Vec<UserId>,
+) -> Result<()> {
+    let event: OtherEvent = serde_json::from_str(raw_event.get())?;
+    let typed_event = parse_typed_event(&event.r#type, raw_event);
+    tracing::debug!("processing event {typed_event:?} {user_ids:?}");
+
+    // Clients are sent the event as Django produced it, unless they lack a
+    // capability that the event's type needs translating for.
+    let legacy_event = match &typed_event {
+        TypedEvent::RealmLinkifiers(RealmLinkifiersEvent {
+            realm_linkifiers, ..
+        }) => Some(legacy_realm_linkifiers_event(
+            &event.attrs,
+            realm_linkifiers,
+        )),
+        _ => None,
+    };
 
     let user_event = ClientEvent::Other {
         r#type: event.r#type,
         attrs: event.attrs,
     };
 
-    let mut queues = state.queues.lock().unwrap();
-
     for user_profile_id in user_ids {
         if let Some(client_keys) = queues.for_user(user_profile_id) {
             for client_key in client_keys.clone() {
                 let client = queues.get_mut(client_key);
+                client.update_user_topics(&typed_event);
                 if client.accepts_event(&user_event) {
-                    client.add_event(user_event.clone());
+                    match &legacy_event {
+                        Some(legacy_event) if !client.info().linkifier_url_template => {
+                            client.add_event(legacy_event.clone());
+                        }
+                        _ => client.add_event(user_event.clone()),
+                    }
                 }
             }
         }
     }
+    Ok(())
 }
 
 #[derive(Debug, Deserialize)]
@@ -1163,38 +1383,328 @@ pub struct Notice<'a> {
     users: &'a RawValue,
 }
 
-pub fn process_notice(state: &Arc<AppState>, notice: Notice) -> Result<()> {
+#[derive(Debug, Deserialize)]
+struct EventType {
+    r#type: String,
+}
+
+fn check_users<'a, T: Deserialize<'a>>(users: &'a RawValue, violations: &mut Vec<String>) {
+    if let Err(err) = serde_json::from_str::<T>(users.get()) {
+        violations.push(format!("users: {err}"));
+    }
+}
+
+/// Check a notice against the models we process it with, returning its
+/// event type and a description of each violation. Besides fields that don't
+/// parse, this reports fields we don't know Django to send, since our models
+/// would otherwise pass those through unnoticed.
+pub fn notice_schema_violations(notice: &Notice) -> (String, Vec<String>) {
+    let Notice { event, users } = *notice;
+    let mut violations = vec![];
+
+    let actual: Value = match serde_json::from_str(event.get()) {
+        Ok(actual) => actual,
+        Err(err) => return ("unknown".to_string(), vec![format!("event: {err}")]),
+    };
+    let event_type = match EventType::deserialize(&actual) {
+        Ok(EventType { r#type }) => r#type,
+        Err(err) => return ("unknown".to_string(), vec![format!("event: {err}")]),
+    };
+
+    match serde_json::from_str::<Event>(event.get()) {
+        Err(err) => violations.push(format!("event: {err}")),
+        Ok(Event::Message(_)) => {
+            check_users::<MessageUsers>(users, &mut violations);
+            unexpected_fields(
+                "event.message_dict",
+                &actual["message_dict"],
+                MESSAGE_DICT_FIELDS,
+                &mut violations,
+            );
+        }
+        Ok(Event::UpdateMessage(_)) => check_users::<Vec<MessageUser>>(users, &mut violations),
+        Ok(Event::DeleteMessage(_)) => {
+            check_users::<DeleteMessageUsers>(users, &mut violations);
+        }
+        Ok(Event::Presence(_) | Event::CustomProfileFields(_)) => {
+            check_users::<Vec<UserId>>(users, &mut violations);
+        }
+        Ok(Event::CleanupQueue(_)) => check_users::<(UserId,)>(users, &mut violations),
+        Ok(Event::Other) => {
+            check_users::<Vec<UserId>>(users, &mut violations);
+            match serde_json::from_str::<TypedEvent>(event.get()) {
+                Err(err) => violations.push(format!("event: {err}")),
+                // We have no schema for this type.
+                Ok(TypedEvent::Unknown) => {}
+                Ok(typed_event) => {
+                    let expected = serde_json::to_value(typed_event).unwrap();
+                    json_diff("event", &expected, &actual, &mut violations);
+                }
+            }
+        }
+    }
+    unexpected_event_fields(&event_type, &actual, &mut violations);
+
+    (event_type, violations)
+}
+
+/// Deliver a notice from Django to the relevant event queues. The caller
+/// holds the queues lock, so that it can process a batch of notices at once.
+pub fn process_notice(state: &Arc<AppState>, queues: &mut Queues, notice: Notice) -> Result<()> {
     tracing::debug!("processing {notice:?}");
 
+    state.notice_validator.check(&notice);
+
     let Notice { event, users } = notice;
 
     match serde_json::from_str::<Event>(event.get())? {
         Event::Message(event) => {
-            process_message_event(state, event, serde_json::from_str(users.get())?)?;
+            process_message_event(state, queues, event, serde_json::from_str(users.get())?)?;
         }
         Event::UpdateMessage(event) => {
-            process_update_message_event(state, event, serde_json::from_str(users.get())?)?;
+            process_update_message_event(state, queues, event, serde_json::from_str(users.get())?)?;
         }
         Event::DeleteMessage(event) => {
-            process_delete_message_event(state, event, serde_json::from_str(users.get())?);
+            process_delete_message_event(queues, event, serde_json::from_str(users.get())?);
         }
         Event::Presence(event) => {
-            process_presence_event(state, event, serde_json::from_str(users.get())?);
+            process_presence_event(queues, event, serde_json::from_str(users.get())?);
         }
         Event::CustomProfileFields(event) => {
-            process_custom_profile_fields_event(state, event, serde_json::from_str(users.get())?);
+            process_custom_profile_fields_event(queues, event, serde_json::from_str(users.get())?);
         }
         Event::CleanupQueue(event) => {
-            process_cleanup_queue_event(state, event, serde_json::from_str(users.get())?);
+            process_cleanup_queue_event(state, queues, event, serde_json::from_str(users.get())?)?;
         }
         Event::Other => {
-            process_other_event(
-                state,
-                serde_json::from_str(event.get())?,
-                serde_json::from_str(users.get())?,
-            );
+            process_other_event(queues, event, serde_json::from_str(users.get())?)?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        enqueue_message_to_public_stream_clients, legacy_realm_linkifiers_event, parse_typed_event,
+        process_other_event, url_template_to_url_format, ClientEvent, WideMessage,
+    };
+    use crate::avatar::AvatarSettings;
+    use crate::event_types::{Linkifier, TypedEvent};
+    use crate::queues::{QueueId, Queues};
+    use serde_json::value::to_raw_value;
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, HashSet};
+
+    const USER_ID: i32 = 10;
+
+    fn register(queues: &mut Queues, client_info: Value) -> QueueId {
+        let mut info = json!({
+            "user_profile_id": USER_ID,
+            "realm_id": 2,
+            "event_types": null,
+            "client_type_name": "website",
+            "apply_markdown": true,
+            "all_public_streams": false,
+            "queue_timeout": 600,
+            "narrow": [],
+        });
+        info.as_object_mut()
+            .unwrap()
+            .extend(client_info.as_object().unwrap().clone());
+        queues.register(serde_json::from_value(info).unwrap())
+    }
+
+    fn send(queues: &mut Queues, event: Value) {
+        process_other_event(queues, &to_raw_value(&event).unwrap(), vec![USER_ID]).unwrap();
+    }
+
+    fn received(queues: &mut Queues, queue_id: QueueId) -> Vec<Value> {
+        let client = queues.by_id(USER_ID, &queue_id).unwrap();
+        client
+            .queue
+            .contents()
+            .map(|event| serde_json::to_value(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn converts_url_templates() {
+        for (url_template, url_format) in [
+            (
+                "https://github.com/zulip/zulip/issues/{id}",
+                Some("https://github.com/zulip/zulip/issues/%(id)s"),
+            ),
+            (
+                "https://example.com/{org}/{repo_name}",
+                Some("https://example.com/%(org)s/%(repo_name)s"),
+            ),
+            (
+                "https://example.com/100%/{id}",
+                Some("https://example.com/100%%/%(id)s"),
+            ),
+            ("https://example.com/", Some("https://example.com/")),
+            ("https://example.com/{?query}", None),
+            ("https://example.com/{+path}", None),
+            ("https://example.com/{}", None),
+            ("https://example.com/{id", None),
+            ("https://example.com/}", None),
+        ] {
+            assert_eq!(
+                url_template_to_url_format(url_template).as_deref(),
+                url_format,
+                "{url_template:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_event_drops_unconvertible_linkifiers() {
+        let linkifiers: Vec<Linkifier> = serde_json::from_value(json!([
+            {"pattern": "#(?P<id>[0-9]+)", "url_template": "https://a.example/{id}", "id": 1},
+            {"pattern": "!(?P<q>.+)", "url_template": "https://b.example/{?q}", "id": 2},
+        ]))
+        .unwrap();
+        let event = legacy_realm_linkifiers_event(&HashMap::new(), &linkifiers);
+        let ClientEvent::Other { r#type, attrs } = event else {
+            panic!("unexpected event");
+        };
+        assert_eq!(r#type, "realm_linkifiers");
+        assert_eq!(
+            attrs["realm_linkifiers"],
+            json!([{"pattern": "#(?P<id>[0-9]+)", "url_format": "https://a.example/%(id)s", "id": 1}])
+        );
+    }
+
+    #[test]
+    fn parses_modeled_events() {
+        let parse = |event: Value| parse_typed_event("", &to_raw_value(&event).unwrap());
+        assert!(matches!(
+            parse(
+                json!({"type": "reaction", "op": "add", "message_id": 1, "user_id": 10,
+                "emoji_name": "smile", "emoji_code": "1f604", "reaction_type": "unicode_emoji"})
+            ),
+            TypedEvent::Reaction(_)
+        ));
+        assert!(matches!(
+            parse(json!({"type": "has_zoom_token", "value": true})),
+            TypedEvent::Unknown
+        ));
+        // A modeled type that doesn't match its model is still delivered.
+        assert!(matches!(
+            parse(json!({"type": "stream", "op": "frobnicate"})),
+            TypedEvent::Unknown
+        ));
+    }
+
+    #[test]
+    fn routes_realm_linkifiers_by_capability() {
+        let mut queues = Queues::new();
+        let legacy = register(&mut queues, json!({}));
+        let modern = register(&mut queues, json!({"linkifier_url_template": true}));
+        let event = json!({
+            "type": "realm_linkifiers",
+            "realm_linkifiers": [
+                {"pattern": "#(?P<id>[0-9]+)", "url_template": "https://a.example/{id}", "id": 1},
+            ],
+        });
+        send(&mut queues, event.clone());
+
+        assert_eq!(received(&mut queues, modern), [event]);
+        assert_eq!(
+            received(&mut queues, legacy),
+            [json!({
+                "type": "realm_linkifiers",
+                "realm_linkifiers": [
+                    {"pattern": "#(?P<id>[0-9]+)", "url_format": "https://a.example/%(id)s", "id": 1},
+                ],
+            })]
+        );
+    }
+
+    #[test]
+    fn routes_subscription_updates_to_user_topics() {
+        let mut queues = Queues::new();
+        let queue_id = register(&mut queues, json!({"narrow": [["is", "muted"]]}));
+        let event = json!({
+            "type": "subscription",
+            "op": "update",
+            "stream_id": 5,
+            "property": "is_muted",
+            "value": true,
+        });
+        send(&mut queues, event.clone());
+
+        let client = queues.by_id(USER_ID, &queue_id).unwrap();
+        assert!(!client.info().user_topics.is_empty());
+        assert_eq!(received(&mut queues, queue_id), [event]);
+    }
+
+    #[test]
+    fn delivers_unparseable_events_as_sent() {
+        let mut queues = Queues::new();
+        let queue_id = register(&mut queues, json!({}));
+        let events = [
+            json!({"type": "stream", "op": "frobnicate", "streams": []}),
+            json!({"type": "has_zoom_token", "value": true}),
+        ];
+        for event in &events {
+            send(&mut queues, event.clone());
+        }
+        assert_eq!(received(&mut queues, queue_id), events);
+    }
+
+    #[test]
+    fn guests_with_narrows_only_get_their_own_messages() {
+        let mut queues = Queues::new();
+        let narrow = json!([["is", "unread"]]);
+        let guest = register(&mut queues, json!({"narrow": narrow}));
+        let member = register(
+            &mut queues,
+            json!({"narrow": narrow, "can_access_public_streams": true}),
+        );
+        let unnarrowed = register(&mut queues, json!({"can_access_public_streams": true}));
+
+        let wide_message: WideMessage = serde_json::from_value(json!({
+            "id": 42,
+            "sender_id": 11,
+            "sender_email": "iago@zulip.com",
+            "sender_delivery_email": "iago@zulip.com",
+            "client": "website",
+            "sender_email_address_visibility": 1,
+            "sender_realm_id": 2,
+            "sender_avatar_source": "G",
+            "sender_avatar_version": 1,
+            "rendered_content": "<p>hi</p>",
+            "content": "hi",
+            "type": "stream",
+            "display_recipient": "Denmark",
+            "subject": "Hello",
+            "stream_id": 5,
+            "recipient_type": 2,
+            "recipient_type_id": 5,
+            "sender_is_mirror_dummy": false,
+        }))
+        .unwrap();
+        enqueue_message_to_public_stream_clients(
+            &mut queues,
+            2,
+            &wide_message,
+            &mut HashMap::new(),
+            &HashSet::new(),
+            None,
+            None,
+            &AvatarSettings {
+                enable_gravatar: false,
+                default_avatar_uri: "https://zulip.example/static/images/default-avatar.png"
+                    .to_string(),
+                avatar_salt: String::new(),
+            },
+        );
+
+        assert!(received(&mut queues, guest).is_empty());
+        assert_eq!(received(&mut queues, member).len(), 1);
+        assert!(received(&mut queues, unnarrowed).is_empty());
+    }
+}
@@ -1,21 +1,36 @@
 use std::collections::HashSet;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::types::{MessageFlags, UserId};
 
-#[derive(Clone, Debug, Serialize)]
+// Deserialized only from stored event queues, where older Zulip versions may
+// be missing some of these.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct UserMessageNotificationsData {
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     pub user_id: UserId,
     pub online_push_enabled: bool,
+    // TODO/compatibility: Remove this alias when one can no longer
+    // directly upgrade from 7.x to main.
+    #[serde(alias = "pm_email_notify")]
     pub dm_email_notify: bool,
+    // TODO/compatibility: Remove this alias when one can no longer
+    // directly upgrade from 7.x to main.
+    #[serde(alias = "pm_push_notify")]
     pub dm_push_notify: bool,
     pub mention_email_notify: bool,
     pub mention_push_notify: bool,
     pub topic_wildcard_mention_email_notify: bool,
     pub topic_wildcard_mention_push_notify: bool,
+    // TODO/compatibility: Remove this alias when one can no longer
+    // directly upgrade from 7.x to main.
+    #[serde(alias = "wildcard_mention_email_notify")]
     pub stream_wildcard_mention_email_notify: bool,
+    // TODO/compatibility: Remove this alias when one can no longer
+    // directly upgrade from 7.x to main.
+    #[serde(alias = "wildcard_mention_push_notify")]
     pub stream_wildcard_mention_push_notify: bool,
     pub stream_push_notify: bool,
     pub stream_email_notify: bool,
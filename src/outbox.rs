@@ -0,0 +1,152 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::app_state::AppState;
+use crate::rabbitmq::Publisher;
+
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How many messages to publish before waiting for their confirms.
+const OUTBOX_BATCH_SIZE: usize = 100;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct OutboxMessage {
+    queue: String,
+    payload: Box<RawValue>,
+}
+
+#[derive(Serialize)]
+pub struct OutboxMetrics {
+    pending: usize,
+    published: u64,
+    publish_failures: u64,
+}
+
+/// Holds missed-message notices until RabbitMQ has confirmed them, so that a
+/// user marked as notified really does get notified.
+#[derive(Default)]
+pub struct Outbox {
+    pending: Mutex<VecDeque<OutboxMessage>>,
+    wake: Notify,
+    published: AtomicU64,
+    publish_failures: AtomicU64,
+}
+
+impl Outbox {
+    pub fn enqueue(&self, queue: &str, message: &impl Serialize) -> Result<()> {
+        let payload = serde_json::value::to_raw_value(message)?;
+        self.pending.lock().unwrap().push_back(OutboxMessage {
+            queue: queue.to_string(),
+            payload,
+        });
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> OutboxMetrics {
+        OutboxMetrics {
+            pending: self.pending.lock().unwrap().len(),
+            published: self.published.load(Ordering::Relaxed),
+            publish_failures: self.publish_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Publish pending messages, a batch at a time, until none are left or
+    /// one fails.
+    async fn publish_pending(&self, publisher: &Publisher) -> Result<()> {
+        loop {
+            // Leave messages in place until they're confirmed, so that they
+            // survive both a failure and cancellation at shutdown.
+            let batch: Vec<OutboxMessage> = {
+                let pending = self.pending.lock().unwrap();
+                pending.iter().take(OUTBOX_BATCH_SIZE).cloned().collect()
+            };
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let messages: Vec<(&str, &[u8])> = batch
+                .iter()
+                .map(|message| (&*message.queue, message.payload.get().as_bytes()))
+                .collect();
+            let results = publisher.publish_all(&messages).await;
+
+            // Nothing but us removes messages, so the batch is still at the
+            // front; put back the ones that failed.
+            let mut first_error = None;
+            let mut pending = self.pending.lock().unwrap();
+            let sent: Vec<OutboxMessage> = pending.drain(..batch.len()).collect();
+            for (message, result) in sent.into_iter().zip(results).rev() {
+                match result {
+                    Ok(()) => {
+                        self.published.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        self.publish_failures.fetch_add(1, Ordering::Relaxed);
+                        pending.push_front(message);
+                        first_error = Some(err);
+                    }
+                }
+            }
+            if let Some(err) = first_error {
+                return Err(err);
+            }
+        }
+    }
+
+    pub fn dump(&self, writer: impl Write) -> serde_json::Result<usize> {
+        let pending = self.pending.lock().unwrap();
+        serde_json::to_writer(writer, &*pending)?;
+        Ok(pending.len())
+    }
+
+    /// Restore messages written by [`Outbox::dump`], returning how many were
+    /// loaded.
+    pub fn load(&self, reader: impl Read) -> serde_json::Result<usize> {
+        let stored: Vec<OutboxMessage> = serde_json::from_reader(reader)?;
+        let count = stored.len();
+        self.pending.lock().unwrap().extend(stored);
+        self.wake.notify_one();
+        Ok(count)
+    }
+}
+
+/// Publish notices as they are enqueued, retrying with exponential backoff
+/// while RabbitMQ is unavailable.
+pub async fn run(state: Arc<AppState>) {
+    let outbox = &state.notification_outbox;
+    let mut shutdown_rx = state.shutdown_rx.clone();
+    let mut delay = MIN_RETRY_DELAY;
+    loop {
+        let result = tokio::select! {
+            result = outbox.publish_pending(&state.rabbitmq) => result,
+            () = shutdown_rx.wait() => break,
+        };
+        if let Err(err) = result {
+            tracing::warn!("failed to publish notification, retrying in {delay:?}: {err:#}");
+            tokio::select! {
+                () = tokio::time::sleep(delay) => {}
+                () = shutdown_rx.wait() => break,
+            }
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        } else {
+            delay = MIN_RETRY_DELAY;
+            tokio::select! {
+                () = outbox.wake.notified() => {}
+                () = shutdown_rx.wait() => break,
+            }
+        }
+    }
+
+    let pending = outbox.pending.lock().unwrap().len();
+    if pending != 0 {
+        tracing::warn!("{pending} notifications were not published before shutdown");
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::outbox::Outbox;
+use crate::queues::Queues;
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// Restore event queues saved by [`dump_event_queues`] or by Tornado's
+/// `dump_event_queues`, if any. Failing to read them is logged rather than
+/// fatal, so clients just re-register.
+pub fn load_event_queues(queues: &mut Queues, path: &Path) {
+    let start = Instant::now();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return,
+        Err(err) => {
+            tracing::error!("could not open event queues file {path:?}: {err}");
+            return;
+        }
+    };
+    match queues.load(BufReader::new(file)) {
+        Ok(count) => tracing::info!(
+            "loaded {count} event queues in {elapsed:?}",
+            elapsed = start.elapsed(),
+        ),
+        Err(err) => tracing::error!("could not deserialize event queues: {err}"),
+    }
+
+    // Move the file aside so that we don't restore stale queues if we crash
+    // before the next graceful shutdown.
+    if let Err(err) = fs::rename(path, with_suffix(path, ".last")) {
+        tracing::warn!("could not rename event queues file {path:?}: {err}");
+    }
+}
+
+/// Save all event queues so that [`load_event_queues`] (or Tornado) can
+/// restore them after a restart.
+pub fn dump_event_queues(queues: &Queues, path: &Path) -> Result<()> {
+    let start = Instant::now();
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut writer = BufWriter::new(
+        File::create(&tmp_path).with_context(|| format!("failed to create {tmp_path:?}"))?,
+    );
+    queues.dump(&mut writer)?;
+    writer.flush()?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("failed to rename {tmp_path:?}"))?;
+    tracing::info!(
+        "dumped event queues to {path:?} in {elapsed:?}",
+        elapsed = start.elapsed(),
+    );
+    Ok(())
+}
+
+/// Restore notifications saved by [`dump_notification_outbox`], if any.
+pub fn load_notification_outbox(outbox: &Outbox, path: &Path) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return,
+        Err(err) => {
+            tracing::error!("could not open notification outbox file {path:?}: {err}");
+            return;
+        }
+    };
+    match outbox.load(BufReader::new(file)) {
+        Ok(count) => tracing::info!("loaded {count} unpublished notifications"),
+        Err(err) => tracing::error!("could not deserialize notification outbox: {err}"),
+    }
+
+    // As with event queues, don't publish these twice if we crash.
+    if let Err(err) = fs::rename(path, with_suffix(path, ".last")) {
+        tracing::warn!("could not rename notification outbox file {path:?}: {err}");
+    }
+}
+
+/// Save notifications that haven't been published yet, so that
+/// [`load_notification_outbox`] can retry them after a restart.
+pub fn dump_notification_outbox(outbox: &Outbox, path: &Path) -> Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut writer = BufWriter::new(
+        File::create(&tmp_path).with_context(|| format!("failed to create {tmp_path:?}"))?,
+    );
+    let count = outbox.dump(&mut writer)?;
+    writer.flush()?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("failed to rename {tmp_path:?}"))?;
+    tracing::info!("dumped {count} unpublished notifications to {path:?}");
+    Ok(())
+}
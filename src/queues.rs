@@ -1,13 +1,21 @@
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
 use slab::Slab;
 use std::borrow::Cow;
 use std::collections::hash_map::{Entry, HashMap};
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::oneshot::{channel, Receiver, Sender};
 use uuid::Uuid;
 
-use crate::narrow::{matches_narrow, Narrow};
+use crate::event_types::TypedEvent;
+use crate::narrow::{
+    delete_message_matches_narrow, is_tornado_compatible, matches_narrow, needs_user_topics,
+    serialize_tornado_narrow, update_message_matches_narrow, MessageLocations, Narrow, UserTopics,
+};
 use crate::notice::{ClientEvent, SpecialClientEvent};
 use crate::types::RealmId;
 use crate::types::UserId;
@@ -19,22 +27,69 @@ pub struct ClientKey(usize);
 
 pub type EventId = i64;
 
-#[derive(Clone, Serialize)]
+/// Default for `lifespan_secs` if the client doesn't specify one.
+pub const DEFAULT_EVENT_QUEUE_TIMEOUT_SECS: u32 = 60 * 10;
+/// Clients can request a `lifespan_secs` as high as this.
+pub const MAX_QUEUE_TIMEOUT_SECS: u32 = 7 * 24 * 60 * 60;
+/// Clients can't request a `lifespan_secs` lower than this, so that a queue
+/// isn't collected (and its missed-message notifications sent) as soon as the
+/// client looks away.
+pub const IDLE_EVENT_QUEUE_TIMEOUT_SECS: u32 = 60 * 10;
+/// We garbage-collect every minute; this means that we might retain an idle
+/// queue for up to a minute past its timeout.
+pub const EVENT_QUEUE_GC_FREQ: Duration = Duration::from_secs(60);
+
+/// An event as kept in a queue, including server-only fields such as a
+/// message's `internal_data`.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ClientEventEntry {
     id: EventId,
     #[serde(flatten)]
     event: ClientEvent,
 }
 
+/// An event as sent to the client, without the fields only the server uses.
+#[derive(Serialize)]
+pub struct WireClientEventEntry {
+    id: EventId,
+    #[serde(flatten)]
+    event: ClientEvent,
+}
+
+impl From<&ClientEventEntry> for WireClientEventEntry {
+    fn from(entry: &ClientEventEntry) -> Self {
+        let event = match &entry.event {
+            ClientEvent::Special(SpecialClientEvent::Message {
+                message,
+                flags,
+                internal_data: _,
+                local_message_id,
+            }) => ClientEvent::Special(SpecialClientEvent::Message {
+                message: Arc::clone(message),
+                flags: flags.clone(),
+                internal_data: None,
+                local_message_id: local_message_id.clone(),
+            }),
+            event => event.clone(),
+        };
+        WireClientEventEntry {
+            id: entry.id,
+            event,
+        }
+    }
+}
+
 pub struct Queue {
     events: VecDeque<ClientEventEntry>,
     next_event_id: EventId,
     sender: Option<Sender<()>>,
+    last_connection_time: Instant,
 }
 
 impl Queue {
-    pub fn peek_events(&mut self, last_event_id: Option<EventId>) -> Vec<ClientEventEntry> {
+    pub fn peek_events(&mut self, last_event_id: Option<EventId>) -> Vec<WireClientEventEntry> {
         self.sender.take();
+        self.last_connection_time = Instant::now();
         if let Some(last_event_id) = last_event_id {
             while let Some(event) = self.events.front() {
                 if event.id > last_event_id {
@@ -43,7 +98,18 @@ impl Queue {
                 self.events.pop_front();
             }
         }
-        self.events.iter().cloned().collect()
+        self.events.iter().map(WireClientEventEntry::from).collect()
+    }
+
+    /// Restart the idle timeout when a client stops waiting for events, as
+    /// Tornado's `disconnect_handler` does, so that a long poll doesn't count
+    /// as idle time.
+    pub fn disconnected(&mut self) {
+        self.last_connection_time = Instant::now();
+    }
+
+    pub fn contents(&self) -> impl Iterator<Item = &ClientEvent> {
+        self.events.iter().map(|entry| &entry.event)
     }
 
     pub fn wait_for_events(&mut self) -> Option<Receiver<()>> {
@@ -53,26 +119,56 @@ impl Queue {
             receiver
         })
     }
+
+    fn connected(&self) -> bool {
+        self.sender
+            .as_ref()
+            .is_some_and(|sender| !sender.is_closed())
+    }
 }
 
+// When loading stored queues, missing keys get the same defaults as
+// Tornado's `ClientDescriptor.from_dict`, so that event queues dumped by
+// older Zulip versions can be restored.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ClientInfo {
     pub user_profile_id: UserId,
     pub realm_id: RealmId,
     pub event_types: Option<HashSet<String>>,
+    // Temporary migration for the rename of client_type to client_type_name
+    #[serde(alias = "client_type")]
     pub client_type_name: Cow<'static, str>,
     pub apply_markdown: bool,
+    #[serde(default)]
     pub client_gravatar: bool,
+    #[serde(default)]
     pub slim_presence: bool,
     pub all_public_streams: bool,
+    /// Whether the user may see public streams they aren't subscribed to,
+    /// which a narrowed queue otherwise receives messages from.
+    #[serde(default)]
+    pub can_access_public_streams: bool,
     pub queue_timeout: u32,
+    #[serde(serialize_with = "serialize_tornado_narrow")]
     pub narrow: Narrow,
+    #[serde(default, skip_serializing_if = "UserTopics::is_empty")]
+    pub user_topics: UserTopics,
+    #[serde(default)]
     pub bulk_message_deletion: bool,
+    #[serde(default)]
     pub stream_typing_notifications: bool,
+    #[serde(default)]
     pub user_settings_object: bool,
+    #[serde(default = "default_pronouns_field_type_supported")]
     pub pronouns_field_type_supported: bool,
+    #[serde(default)]
     pub linkifier_url_template: bool,
 }
 
+fn default_pronouns_field_type_supported() -> bool {
+    true
+}
+
 pub struct Client {
     info: ClientInfo,
     pub queue_id: QueueId,
@@ -92,6 +188,12 @@ impl Client {
             .is_some_and(|event_types| !event_types.contains(event_type))
     }
 
+    fn expired(&self, now: Instant) -> bool {
+        !self.queue.connected()
+            && now.saturating_duration_since(self.queue.last_connection_time)
+                >= Duration::from_secs(self.info.queue_timeout.into())
+    }
+
     // TODO: Refactor so we don't need this function
     pub fn accepts_messages(&self) -> bool {
         self.accepts_type("message")
@@ -100,13 +202,51 @@ impl Client {
     pub fn accepts_event(&self, event: &ClientEvent) -> bool {
         match event {
             ClientEvent::Special(SpecialClientEvent::Message { message, flags, .. }) => {
-                self.accepts_type("message") && matches_narrow(message, flags, &self.info.narrow)
+                self.accepts_type("message")
+                    && matches_narrow(
+                        message,
+                        flags,
+                        &self.info.narrow,
+                        self.info.user_profile_id,
+                        &self.info.user_topics,
+                    )
             }
-            ClientEvent::Special(SpecialClientEvent::UpdateMessage { .. }) => {
+            ClientEvent::Special(SpecialClientEvent::UpdateMessage {
+                attrs,
+                stream_name,
+                message_id,
+                flags,
+                stored_location,
+                ..
+            }) => {
                 self.accepts_type("update_message")
+                    && update_message_matches_narrow(
+                        attrs,
+                        stream_name.as_deref(),
+                        *message_id,
+                        flags,
+                        stored_location.as_deref(),
+                        &self.info.narrow,
+                        &self.info.user_topics,
+                    )
             }
-            ClientEvent::Special(SpecialClientEvent::DeleteMessage { .. }) => {
+            ClientEvent::Special(SpecialClientEvent::DeleteMessage {
+                attrs,
+                message_ids,
+                message_id,
+                stored_location,
+            }) => {
                 self.accepts_type("delete_message")
+                    && delete_message_matches_narrow(
+                        attrs,
+                        message_ids
+                            .as_deref()
+                            .or(message_id.as_ref().map(std::slice::from_ref))
+                            .unwrap_or_default(),
+                        stored_location.as_deref(),
+                        &self.info.narrow,
+                        &self.info.user_topics,
+                    )
             }
             ClientEvent::Special(SpecialClientEvent::Presence { .. }) => {
                 self.accepts_type("presence")
@@ -137,13 +277,27 @@ impl Client {
                     "update_display_settings" | "update_global_notifications" => {
                         !self.info.user_settings_object
                     }
+                    // Only the web app knows how to reload itself.
+                    "web_reload_client" => self.info.client_type_name == "website",
                     _ => true,
                 }
             }
         }
     }
 
+    /// Track the state that the client's narrow depends on.
+    pub fn update_user_topics(&mut self, event: &TypedEvent) {
+        if needs_user_topics(&self.info.narrow) {
+            self.info.user_topics.apply_event(event);
+        }
+    }
+
     pub fn add_event(&mut self, event: ClientEvent) {
+        // Like Tornado's virtual events, only the most recent restart event
+        // needs to be kept.
+        if is_restart(&event) {
+            self.queue.events.retain(|entry| !is_restart(&entry.event));
+        }
         self.queue.events.push_back(ClientEventEntry {
             id: self.queue.next_event_id,
             event,
@@ -155,11 +309,16 @@ impl Client {
     }
 }
 
+fn is_restart(event: &ClientEvent) -> bool {
+    matches!(event, ClientEvent::Other { r#type, .. } if r#type == "restart")
+}
+
 pub struct Queues {
     clients: Slab<Client>,
     clients_by_queue_id: HashMap<QueueId, ClientKey>,
     user_clients: HashMap<UserId, HashSet<ClientKey>>,
     realm_clients_all_streams: HashMap<RealmId, HashSet<ClientKey>>,
+    pub message_locations: MessageLocations,
 }
 
 impl Queues {
@@ -169,28 +328,37 @@ impl Queues {
             clients_by_queue_id: HashMap::new(),
             user_clients: HashMap::new(),
             realm_clients_all_streams: HashMap::new(),
+            message_locations: MessageLocations::default(),
         }
     }
 
     pub fn register(&mut self, info: ClientInfo) -> QueueId {
-        let ClientInfo {
-            user_profile_id: user_id,
-            realm_id,
-            all_public_streams,
-            ..
-        } = info;
-        let narrow_empty = info.narrow.is_empty();
-
         let queue_id = Uuid::new_v4();
-        let client_key = ClientKey(self.clients.insert(Client {
+        self.insert(Client {
             info,
             queue_id,
             queue: Queue {
                 events: VecDeque::new(),
                 next_event_id: 0,
                 sender: None,
+                last_connection_time: Instant::now(),
             },
-        }));
+        });
+        queue_id
+    }
+
+    fn insert(&mut self, client: Client) {
+        let ClientInfo {
+            user_profile_id: user_id,
+            realm_id,
+            all_public_streams,
+            can_access_public_streams,
+            ..
+        } = client.info;
+        let narrow_empty = client.info.narrow.is_empty();
+        let queue_id = client.queue_id;
+
+        let client_key = ClientKey(self.clients.insert(client));
         assert!(self
             .clients_by_queue_id
             .insert(queue_id, client_key)
@@ -199,14 +367,12 @@ impl Queues {
             .entry(user_id)
             .or_default()
             .insert(client_key);
-        if all_public_streams || !narrow_empty {
+        if all_public_streams || (!narrow_empty && can_access_public_streams) {
             self.realm_clients_all_streams
                 .entry(realm_id)
                 .or_default()
                 .insert(client_key);
         }
-
-        queue_id
     }
 
     pub fn get(&self, key: ClientKey) -> &Client {
@@ -217,6 +383,10 @@ impl Queues {
         &mut self.clients[key.0]
     }
 
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Client> {
+        self.clients.iter_mut().map(|(_, client)| client)
+    }
+
     pub fn by_id(&mut self, user_id: UserId, queue_id: &QueueId) -> Option<&mut Client> {
         let client = &mut self.clients[self.clients_by_queue_id.get(queue_id)?.0];
         (client.info.user_profile_id == user_id).then_some(client)
@@ -230,17 +400,32 @@ impl Queues {
         self.realm_clients_all_streams.get(&realm_id)
     }
 
-    pub fn delete(&mut self, user_id: UserId, queue_id: QueueId) -> bool {
-        let Entry::Occupied(entry) = self.clients_by_queue_id.entry(queue_id) else {
-            return false;
-        };
-        let client_key = *entry.get();
-        let client = &self.clients[client_key.0];
-        if client.info.user_profile_id != user_id {
-            return false;
+    pub fn delete(&mut self, user_id: UserId, queue_id: QueueId) -> Option<Client> {
+        let &client_key = self.clients_by_queue_id.get(&queue_id)?;
+        if self.clients[client_key.0].info.user_profile_id != user_id {
+            return None;
         }
+        Some(self.remove(client_key))
+    }
 
-        entry.remove();
+    /// Remove all clients whose queues have been idle for longer than their
+    /// timeout, returning them.
+    pub fn gc(&mut self, now: Instant) -> Vec<Client> {
+        let expired: Vec<ClientKey> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.expired(now))
+            .map(|(key, _)| ClientKey(key))
+            .collect();
+        expired
+            .into_iter()
+            .map(|client_key| self.remove(client_key))
+            .collect()
+    }
+
+    fn remove(&mut self, client_key: ClientKey) -> Client {
+        let client = self.clients.remove(client_key.0);
+        self.clients_by_queue_id.remove(&client.queue_id);
         if let Entry::Occupied(mut entry) = self.user_clients.entry(client.info.user_profile_id) {
             let clients = entry.get_mut();
             if clients.remove(&client_key) && clients.is_empty() {
@@ -255,7 +440,275 @@ impl Queues {
                 entry.remove();
             }
         }
-        self.clients.remove(client_key.0);
-        true
+        client
+    }
+
+    /// Write all clients and their queues to `writer`, in the same layout as
+    /// Tornado's `dump_event_queues`.
+    pub fn dump(&self, writer: impl Write) -> serde_json::Result<()> {
+        let stored: Vec<(QueueId, StoredClient)> = self
+            .clients
+            .iter()
+            .map(|(_, client)| {
+                (
+                    client.queue_id,
+                    StoredClient {
+                        info: Cow::Borrowed(&client.info),
+                        boq_narrow: (!is_tornado_compatible(&client.info.narrow))
+                            .then_some(Cow::Borrowed(&client.info.narrow)),
+                        event_queue: StoredQueue {
+                            id: client.queue_id,
+                            next_event_id: client.queue.next_event_id,
+                            queue: Cow::Borrowed(&client.queue.events),
+                            virtual_events: HashMap::new(),
+                        },
+                        last_connection_time: client.queue.last_connection_time,
+                    },
+                )
+            })
+            .collect();
+        serde_json::to_writer(writer, &stored)
+    }
+
+    /// Restore clients written by [`Queues::dump`] or by Tornado, returning
+    /// how many were loaded. Clients that can't be parsed are skipped.
+    pub fn load(&mut self, reader: impl Read) -> serde_json::Result<usize> {
+        let stored: Vec<(QueueId, Box<RawValue>)> = serde_json::from_reader(reader)?;
+        let mut count = 0;
+        for (queue_id, client) in stored {
+            if self.clients_by_queue_id.contains_key(&queue_id) {
+                tracing::warn!("Ignoring duplicate stored queue id {queue_id}");
+                continue;
+            }
+            let client: StoredClient = match serde_json::from_str(client.get()) {
+                Ok(client) => client,
+                Err(err) => {
+                    tracing::warn!("Ignoring bad stored queue {queue_id}: {err}");
+                    continue;
+                }
+            };
+
+            let mut events = client.event_queue.queue.into_owned();
+            if !client.event_queue.virtual_events.is_empty() {
+                events.extend(client.event_queue.virtual_events.into_values());
+                events.make_contiguous().sort_by_key(|entry| entry.id);
+            }
+
+            let mut info = client.info.into_owned();
+            if let Some(narrow) = client.boq_narrow {
+                info.narrow = narrow.into_owned();
+            }
+            self.insert(Client {
+                info,
+                queue_id,
+                queue: Queue {
+                    events,
+                    next_event_id: client.event_queue.next_event_id,
+                    sender: None,
+                    last_connection_time: client.last_connection_time,
+                },
+            });
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredQueue<'a> {
+    id: QueueId,
+    next_event_id: EventId,
+    queue: Cow<'a, VecDeque<ClientEventEntry>>,
+    // Tornado keeps the latest event of some collapsible types here rather
+    // than in `queue`. We never produce these, but write the key so that
+    // Tornado can load our dumps.
+    #[serde(default)]
+    virtual_events: HashMap<String, ClientEventEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredClient<'a> {
+    #[serde(flatten)]
+    info: Cow<'a, ClientInfo>,
+    /// The full narrow, if `narrow` only has the part that Tornado can load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    boq_narrow: Option<Cow<'a, Narrow>>,
+    event_queue: StoredQueue<'a>,
+    #[serde(with = "unix_time")]
+    last_connection_time: Instant,
+}
+
+/// Store an [`Instant`] as a floating-point Unix timestamp, like Python's
+/// `time.time()`.
+mod unix_time {
+    use super::{
+        Deserialize, Deserializer, Duration, Instant, Serialize, Serializer, SystemTime, UNIX_EPOCH,
+    };
+
+    pub fn serialize<S: Serializer>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        let time = SystemTime::now()
+            .checked_sub(instant.elapsed())
+            .unwrap_or(UNIX_EPOCH);
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        let timestamp = f64::deserialize(deserializer)?;
+        let now = Instant::now();
+        let elapsed = Duration::try_from_secs_f64(timestamp)
+            .ok()
+            .and_then(|since_epoch| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH + since_epoch)
+                    .ok()
+            })
+            .unwrap_or_default();
+        Ok(now.checked_sub(elapsed).unwrap_or(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventId, QueueId, Queues};
+    use serde_json::{json, Value};
+
+    const QUEUE_ID: &str = "a6a7c9a4-65b5-4b55-bd5e-2fe3a1ad1d32";
+
+    /// A queue as dumped by an older Tornado, without the newer client
+    /// options and with a collapsed presence event in `virtual_events`.
+    fn tornado_dump() -> Value {
+        let event = |id: EventId, r#type: &str| json!({"id": id, "type": r#type, "op": "add"});
+        json!([[
+            QUEUE_ID,
+            {
+                "user_profile_id": 10,
+                "realm_id": 2,
+                "event_types": null,
+                "client_type": "website",
+                "apply_markdown": true,
+                "all_public_streams": false,
+                "queue_timeout": 600,
+                "narrow": [["stream", "Denmark"]],
+                "last_connection_time": 1700000000.5,
+                "event_queue": {
+                    "id": QUEUE_ID,
+                    "next_event_id": 4,
+                    "queue": [event(1, "reaction"), event(3, "reaction")],
+                    "virtual_events": {"presence": event(2, "presence")},
+                },
+            },
+        ]])
+    }
+
+    fn event_ids(queues: &mut Queues) -> Vec<EventId> {
+        let queue_id: QueueId = QUEUE_ID.parse().unwrap();
+        let client = queues.by_id(10, &queue_id).unwrap();
+        client.queue.events.iter().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn loads_tornado_dump() {
+        let mut queues = Queues::new();
+        let dump = serde_json::to_vec(&tornado_dump()).unwrap();
+        assert_eq!(queues.load(&dump[..]).unwrap(), 1);
+        assert_eq!(event_ids(&mut queues), [1, 2, 3]);
+
+        let queue_id: QueueId = QUEUE_ID.parse().unwrap();
+        let client = queues.by_id(10, &queue_id).unwrap();
+        assert_eq!(client.queue.next_event_id, 4);
+        let info = client.info();
+        assert_eq!(info.client_type_name, "website");
+        assert!(!info.client_gravatar);
+        assert!(info.pronouns_field_type_supported);
+
+        // Queues that are already loaded aren't loaded again.
+        assert_eq!(queues.load(&dump[..]).unwrap(), 0);
+    }
+
+    #[test]
+    fn dump_round_trips() {
+        let mut queues = Queues::new();
+        let dump = serde_json::to_vec(&tornado_dump()).unwrap();
+        queues.load(&dump[..]).unwrap();
+
+        let mut dump = Vec::new();
+        queues.dump(&mut dump).unwrap();
+        let dumped: Value = serde_json::from_slice(&dump).unwrap();
+        let client = &dumped[0][1];
+        assert_eq!(dumped[0][0], QUEUE_ID);
+        assert_eq!(client["client_type_name"], "website");
+        assert_eq!(client["narrow"], json!([["stream", "Denmark"]]));
+        assert_eq!(client["event_queue"]["id"], QUEUE_ID);
+        assert_eq!(client["event_queue"]["next_event_id"], 4);
+        assert_eq!(client["event_queue"]["virtual_events"], json!({}));
+        assert_eq!(client["event_queue"]["queue"][1]["type"], "presence");
+        let last_connection_time = client["last_connection_time"].as_f64().unwrap();
+        assert!((last_connection_time - 1700000000.5).abs() < 1.0);
+
+        let mut reloaded = Queues::new();
+        assert_eq!(reloaded.load(&dump[..]).unwrap(), 1);
+        assert_eq!(event_ids(&mut reloaded), [1, 2, 3]);
+    }
+
+    #[test]
+    fn skips_bad_queues() {
+        let mut dump = tornado_dump();
+        dump.as_array_mut().unwrap().push(json!([
+            "0f0d3c5e-8d43-4a5b-9a43-6b3c1a1e9b7e",
+            {"user_profile_id": 11},
+        ]));
+        let mut queues = Queues::new();
+        let dump = serde_json::to_vec(&dump).unwrap();
+        assert_eq!(queues.load(&dump[..]).unwrap(), 1);
+    }
+
+    fn dump(queues: &Queues) -> Value {
+        let mut dump = Vec::new();
+        queues.dump(&mut dump).unwrap();
+        serde_json::from_slice(&dump).unwrap()
+    }
+
+    #[test]
+    fn dumps_narrows_tornado_can_load() {
+        let narrow = json!([
+            ["channel", "Denmark"],
+            ["-topic", "hello"],
+            ["is", "dm"],
+            ["dm", [11]],
+        ]);
+        let mut stored = tornado_dump();
+        stored[0][1]["narrow"] = narrow.clone();
+        let mut queues = Queues::new();
+        queues
+            .load(&serde_json::to_vec(&stored).unwrap()[..])
+            .unwrap();
+
+        let dumped = dump(&queues);
+        let client = &dumped[0][1];
+        assert_eq!(
+            client["narrow"],
+            json!([["stream", "Denmark"], ["is", "private"]])
+        );
+        assert_eq!(client["boq_narrow"], narrow);
+
+        let mut reloaded = Queues::new();
+        reloaded
+            .load(&serde_json::to_vec(&dumped).unwrap()[..])
+            .unwrap();
+        assert_eq!(dump(&reloaded)[0][1]["boq_narrow"], narrow);
+    }
+
+    #[test]
+    fn omits_full_narrow_when_tornado_can_load_it() {
+        let mut queues = Queues::new();
+        queues
+            .load(&serde_json::to_vec(&tornado_dump()).unwrap()[..])
+            .unwrap();
+        let dumped = dump(&queues);
+        assert_eq!(dumped[0][1]["narrow"], json!([["stream", "Denmark"]]));
+        assert!(dumped[0][1].get("boq_narrow").is_none());
     }
 }
@@ -1,49 +1,235 @@
-use amq_protocol_types::FieldTable;
-use amq_protocol_uri::{AMQPAuthority, AMQPQueryString, AMQPScheme, AMQPUri, AMQPUserInfo};
+use amq_protocol_types::{AMQPValue, FieldTable};
+use amq_protocol_uri::AMQPUri;
 use anyhow::Result;
-use futures_lite::StreamExt;
+use futures_lite::{future, StreamExt};
 use lapin::message::Delivery;
-use lapin::options::{BasicAckOptions, BasicConsumeOptions, BasicQosOptions, QueueDeclareOptions};
-use lapin::{Channel, Connection, ConnectionProperties, Consumer};
-use std::sync::Arc;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
+    ConfirmSelectOptions, QueueDeclareOptions,
+};
+use lapin::tcp::{OwnedIdentity, OwnedTLSConfig};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, Consumer};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::app_state::AppState;
 use crate::notice::process_notice;
 
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// A PKCS #12 client certificate and key, and the password to decrypt it.
+#[derive(Clone)]
+pub struct TlsIdentity {
+    pub der: Vec<u8>,
+    pub password: String,
+}
+
+#[derive(Clone)]
+pub struct RabbitMQSettings {
+    pub uri: AMQPUri,
+    /// Extra CA certificates to trust for `amqps` connections, in PEM format.
+    pub tls_ca_certs: Option<String>,
+    pub tls_client_identity: Option<TlsIdentity>,
+    pub prefetch_count: u16,
+    pub notify_queue: String,
+    pub dead_letter_queue: Option<String>,
+}
+
+impl RabbitMQSettings {
+    fn tls_config(&self) -> OwnedTLSConfig {
+        OwnedTLSConfig {
+            identity: self
+                .tls_client_identity
+                .as_ref()
+                .map(|identity| OwnedIdentity {
+                    der: identity.der.clone(),
+                    password: identity.password.clone(),
+                }),
+            cert_chain: self.tls_ca_certs.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting { attempt: u32, error: String },
+}
+
+#[derive(Serialize)]
+pub struct FailedNoticeMetrics {
+    dead_lettered: u64,
+    rejected: u64,
+    dead_letter_failures: u64,
+}
+
+/// Counts of notices we failed to process, by how we settled them.
+#[derive(Default)]
+struct FailedNotices {
+    /// Published to the dead-letter queue.
+    dead_lettered: AtomicU64,
+    /// Discarded, because there's no dead-letter queue or publishing to it
+    /// failed.
+    rejected: AtomicU64,
+    /// Dead-letter publishes that failed or that RabbitMQ nacked.
+    dead_letter_failures: AtomicU64,
+}
+
+/// How to settle a delivery we failed to process.
+#[derive(Debug, PartialEq)]
+enum Settlement {
+    Ack,
+    Reject,
+}
+
+impl FailedNotices {
+    /// Decide how to settle a failed delivery given the result of publishing
+    /// it to the dead-letter queue, if there is one. A failed publish is
+    /// logged and the delivery rejected, so that one bad notice can never
+    /// stop us from consuming the rest.
+    fn settle(&self, dead_letter_result: Option<Result<()>>, delivery_tag: u64) -> Settlement {
+        match dead_letter_result {
+            Some(Ok(())) => {
+                self.dead_lettered.fetch_add(1, Ordering::Relaxed);
+                Settlement::Ack
+            }
+            Some(Err(err)) => {
+                tracing::error!(
+                    "failed to dead-letter notice, discarding it (delivery_tag={delivery_tag}): {err:#}"
+                );
+                self.dead_letter_failures.fetch_add(1, Ordering::Relaxed);
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Settlement::Reject
+            }
+            None => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Settlement::Reject
+            }
+        }
+    }
+
+    fn metrics(&self) -> FailedNoticeMetrics {
+        FailedNoticeMetrics {
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dead_letter_failures: self.dead_letter_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The channel that the rest of boq publishes on, which is replaced each time
+/// we reconnect.
+pub struct Publisher {
+    channel: Mutex<Channel>,
+    connection_state: Mutex<ConnectionState>,
+    failed_notices: FailedNotices,
+}
+
+impl Publisher {
+    pub fn new(channel: Channel) -> Publisher {
+        Publisher {
+            channel: Mutex::new(channel),
+            connection_state: Mutex::new(ConnectionState::Connected),
+            failed_notices: FailedNotices::default(),
+        }
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.connection_state.lock().unwrap().clone()
+    }
+
+    pub fn failed_notice_metrics(&self) -> FailedNoticeMetrics {
+        self.failed_notices.metrics()
+    }
+
+    fn set_connected(&self, channel: Channel) {
+        *self.channel.lock().unwrap() = channel;
+        *self.connection_state.lock().unwrap() = ConnectionState::Connected;
+    }
+
+    fn set_reconnecting(&self, attempt: u32, error: String) {
+        *self.connection_state.lock().unwrap() = ConnectionState::Reconnecting { attempt, error };
+    }
+
+    /// Publish persistent messages to their queues, sending them all before
+    /// waiting for RabbitMQ to confirm any. Returns a result per message.
+    pub async fn publish_all(&self, messages: &[(&str, &[u8])]) -> Vec<Result<()>> {
+        let channel = self.channel.lock().unwrap().clone();
+        let mut confirms = vec![];
+        for &(queue, payload) in messages {
+            let confirm = channel
+                .basic_publish(
+                    "",
+                    queue,
+                    BasicPublishOptions::default(),
+                    payload,
+                    BasicProperties::default().with_delivery_mode(2),
+                )
+                .await;
+            confirms.push((queue, confirm));
+        }
+
+        let mut results = vec![];
+        for (queue, confirm) in confirms {
+            results.push(
+                async {
+                    let confirmation = confirm?.await?;
+                    anyhow::ensure!(
+                        confirmation.is_ack(),
+                        "RabbitMQ did not confirm message to {queue}"
+                    );
+                    Ok(())
+                }
+                .await,
+            );
+        }
+        results
+    }
+}
+
 pub struct RabbitMQ {
+    settings: RabbitMQSettings,
+    connection: Connection,
     pub channel: Channel,
     consumer: Consumer,
 }
 
 impl RabbitMQ {
-    pub async fn connect(
-        host: String,
-        username: String,
-        password: String,
-        notify_queue: &str,
-    ) -> Result<RabbitMQ> {
-        let port = 5672;
-        tracing::debug!("connecting to queue on {username}@{host}:{port}");
-
-        let connection = Connection::connect_uri(
-            AMQPUri {
-                scheme: AMQPScheme::AMQP,
-                authority: AMQPAuthority {
-                    userinfo: AMQPUserInfo { username, password },
-                    host,
-                    port,
-                },
-                vhost: "/".into(),
-                query: AMQPQueryString::default(),
-            },
+    pub async fn connect(settings: RabbitMQSettings) -> Result<RabbitMQ> {
+        let AMQPUri {
+            scheme,
+            authority,
+            vhost,
+            ..
+        } = &settings.uri;
+        tracing::debug!(
+            "connecting to queue on {username}@{host}:{port} (vhost {vhost:?}, {scheme:?})",
+            username = authority.userinfo.username,
+            host = authority.host,
+            port = authority.port,
+        );
+
+        let connection = Connection::connect_uri_with_config(
+            settings.uri.clone(),
             ConnectionProperties::default()
                 .with_executor(tokio_executor_trait::Tokio::current())
                 .with_reactor(tokio_reactor_trait::Tokio),
+            settings.tls_config(),
         )
         .await?;
 
         let channel = connection.create_channel().await?;
-        channel.basic_qos(100, BasicQosOptions::default()).await?;
+        channel
+            .basic_qos(settings.prefetch_count, BasicQosOptions::default())
+            .await?;
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
 
         let declare = {
             let channel = channel.clone();
@@ -64,62 +250,287 @@ impl RabbitMQ {
                 }
             }
         };
-        let (notify_result, mobile_result, emails_result) = tokio::join!(
-            declare(notify_queue),
+        let (notify_result, mobile_result, emails_result, activity_result) = tokio::join!(
+            declare(&settings.notify_queue),
             declare("missedmessage_mobile_notifications"),
             declare("missedmessage_emails"),
+            declare("user_activity"),
         );
         notify_result?;
         mobile_result?;
         emails_result?;
+        activity_result?;
+        if let Some(dead_letter_queue) = &settings.dead_letter_queue {
+            declare(dead_letter_queue).await?;
+        }
 
         let notify_consumer = channel
             .basic_consume(
-                notify_queue,
+                &settings.notify_queue,
                 "consumer",
                 BasicConsumeOptions::default(),
                 FieldTable::default(),
             )
             .await?;
 
-        tracing::debug!("listening on queue {notify_queue}");
+        tracing::debug!("listening on queue {}", settings.notify_queue);
 
         Ok(RabbitMQ {
+            settings,
+            connection,
             channel,
             consumer: notify_consumer,
         })
     }
 
-    async fn handle_delivery(&mut self, state: &Arc<AppState>, delivery: Delivery) -> Result<()> {
-        tracing::debug!(
-            "RabbitMQ: delivery_tag={:?} exchange={:?} routing_key={:?} redelivered={:?} data={:?}",
+    /// Wait for a delivery, then take any others that have already arrived,
+    /// up to the prefetch count.
+    async fn next_batch(&mut self) -> Result<Vec<Delivery>> {
+        let Some(delivery) = self.consumer.next().await else {
+            anyhow::bail!("RabbitMQ connection closed");
+        };
+        let mut batch = vec![delivery?];
+        while batch.len() < usize::from(self.settings.prefetch_count) {
+            let Some(Some(delivery)) = future::poll_once(self.consumer.next()).await else {
+                break;
+            };
+            batch.push(delivery?);
+        }
+        Ok(batch)
+    }
+
+    /// Process a batch of deliveries in order under a single acquisition of
+    /// the queues lock, then acknowledge them together.
+    async fn handle_batch(&self, state: &Arc<AppState>, batch: Vec<Delivery>) -> Result<()> {
+        let failures: Vec<(&Delivery, anyhow::Error)> = {
+            let mut queues = state.queues.lock().unwrap();
+            batch
+                .iter()
+                .filter_map(|delivery| {
+                    tracing::debug!(
+                        "RabbitMQ: delivery_tag={:?} exchange={:?} routing_key={:?} redelivered={:?} data={:?}",
+                        delivery.delivery_tag,
+                        delivery.exchange,
+                        delivery.routing_key,
+                        delivery.redelivered,
+                        String::from_utf8_lossy(&delivery.data),
+                    );
+                    if state
+                        .recent_notices
+                        .is_duplicate(delivery.redelivered, &delivery.data)
+                    {
+                        tracing::info!(
+                            "skipping already processed notice (delivery_tag={})",
+                            delivery.delivery_tag,
+                        );
+                        return None;
+                    }
+                    let result = serde_json::from_slice(&delivery.data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|notice| process_notice(state, &mut queues, notice));
+                    match result {
+                        Ok(()) => {
+                            state.recent_notices.insert(&delivery.data);
+                            None
+                        }
+                        Err(err) => Some((delivery, err)),
+                    }
+                })
+                .collect()
+        };
+
+        // Settle failures individually first, so that the multiple ack
+        // below only covers deliveries we processed.
+        for (delivery, err) in &failures {
+            self.dead_letter(state, delivery, err).await?;
+        }
+        let last_processed = batch.iter().rev().find(|delivery| {
+            !failures
+                .iter()
+                .any(|(failed, _)| failed.delivery_tag == delivery.delivery_tag)
+        });
+        if let Some(delivery) = last_processed {
+            self.channel
+                .basic_ack(delivery.delivery_tag, BasicAckOptions { multiple: true })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Set aside a delivery we failed to process, so that one bad notice
+    /// doesn't stop us from consuming the rest. Only a failure to settle the
+    /// delivery, which means the channel is gone, is returned.
+    async fn dead_letter(
+        &self,
+        state: &AppState,
+        delivery: &Delivery,
+        err: &anyhow::Error,
+    ) -> Result<()> {
+        tracing::error!(
+            "failed to process notice (delivery_tag={}): {err:#}",
             delivery.delivery_tag,
-            delivery.exchange,
-            delivery.routing_key,
-            delivery.redelivered,
-            String::from_utf8_lossy(&delivery.data),
         );
-        process_notice(state, serde_json::from_slice(&delivery.data)?)?;
-        self.channel
-            .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+
+        let dead_letter_result = match &self.settings.dead_letter_queue {
+            Some(dead_letter_queue) => Some(
+                self.publish_dead_letter(dead_letter_queue, delivery, err)
+                    .await,
+            ),
+            None => None,
+        };
+        match state
+            .rabbitmq
+            .failed_notices
+            .settle(dead_letter_result, delivery.delivery_tag)
+        {
+            Settlement::Ack => {
+                self.channel
+                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+                    .await?;
+            }
+            Settlement::Reject => {
+                self.channel
+                    .basic_nack(
+                        delivery.delivery_tag,
+                        BasicNackOptions {
+                            requeue: false,
+                            ..BasicNackOptions::default()
+                        },
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn publish_dead_letter(
+        &self,
+        dead_letter_queue: &str,
+        delivery: &Delivery,
+        err: &anyhow::Error,
+    ) -> Result<()> {
+        let mut headers = FieldTable::default();
+        headers.insert(
+            "x-boq-error".into(),
+            AMQPValue::LongString(format!("{err:#}").into()),
+        );
+        headers.insert(
+            "x-boq-routing-key".into(),
+            AMQPValue::LongString(delivery.routing_key.as_str().into()),
+        );
+        let confirmation = self
+            .channel
+            .basic_publish(
+                "",
+                dead_letter_queue,
+                BasicPublishOptions::default(),
+                &delivery.data,
+                BasicProperties::default()
+                    .with_delivery_mode(2)
+                    .with_headers(headers),
+            )
+            .await?
             .await?;
+        anyhow::ensure!(
+            confirmation.is_ack(),
+            "RabbitMQ did not confirm message to {dead_letter_queue}"
+        );
         Ok(())
     }
 
-    pub async fn run(mut self, state: Arc<AppState>) -> Result<()> {
+    /// Consume notices until the connection, channel or consumer fails.
+    /// Notices that fail to process are settled in [`RabbitMQ::dead_letter`]
+    /// rather than returned.
+    async fn consume(&mut self, state: &Arc<AppState>) -> Result<Infallible> {
+        loop {
+            let batch = self.next_batch().await?;
+            self.handle_batch(state, batch).await?;
+        }
+    }
+
+    /// Close the connection, so that RabbitMQ requeues any deliveries we
+    /// haven't acknowledged instead of holding them for a consumer that's
+    /// gone.
+    async fn close(&self) {
+        if !self.connection.status().connected() {
+            return;
+        }
+        if let Err(err) = self.connection.close(200, "closing").await {
+            tracing::debug!("failed to close RabbitMQ connection: {err}");
+        }
+    }
+
+    /// Reconnect with exponential backoff, or return `None` if we're shutting
+    /// down first.
+    async fn reconnect(&self, state: &Arc<AppState>, mut error: anyhow::Error) -> Option<RabbitMQ> {
         let mut shutdown_rx = state.shutdown_rx.clone();
+        let mut delay = MIN_RECONNECT_DELAY;
+        let mut attempt = 0;
         loop {
+            attempt += 1;
+            tracing::warn!("RabbitMQ connection lost, reconnecting in {delay:?}: {error:#}");
+            state
+                .rabbitmq
+                .set_reconnecting(attempt, format!("{error:#}"));
             tokio::select! {
-                delivery = self.consumer.next() => {
-                    let Some(delivery) = delivery else {
-                        tracing::warn!("RabbitMQ connection closed");
-                        break;
-                    };
-                    self.handle_delivery(&state, delivery?).await?;
+                () = tokio::time::sleep(delay) => {}
+                () = shutdown_rx.wait() => return None,
+            }
+            match RabbitMQ::connect(self.settings.clone()).await {
+                Ok(rabbitmq) => {
+                    tracing::info!("reconnected to RabbitMQ after {attempt} attempts");
+                    state.rabbitmq.set_connected(rabbitmq.channel.clone());
+                    return Some(rabbitmq);
                 }
-                () = shutdown_rx.wait() => break,
+                Err(err) => error = err,
             }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
         }
+    }
+
+    /// Consume notices until shutdown, closing the connection and
+    /// reconnecting whenever consuming fails, so that our unacknowledged
+    /// deliveries are requeued.
+    pub async fn run(mut self, state: Arc<AppState>) -> Result<()> {
+        let mut shutdown_rx = state.shutdown_rx.clone();
+        loop {
+            let Err(error) = tokio::select! {
+                result = self.consume(&state) => result,
+                () = shutdown_rx.wait() => break,
+            };
+            self.close().await;
+            let Some(rabbitmq) = self.reconnect(&state, error).await else {
+                return Ok(());
+            };
+            self = rabbitmq;
+        }
+        self.close().await;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FailedNotices, Settlement};
+
+    #[test]
+    fn failed_dead_letter_publish_rejects_delivery() {
+        let failed_notices = FailedNotices::default();
+        assert_eq!(
+            failed_notices.settle(Some(Err(anyhow::anyhow!("nacked"))), 1),
+            Settlement::Reject
+        );
+        assert_eq!(failed_notices.settle(Some(Ok(())), 2), Settlement::Ack);
+        assert_eq!(failed_notices.settle(None, 3), Settlement::Reject);
+
+        let metrics = serde_json::to_value(failed_notices.metrics()).unwrap();
+        assert_eq!(
+            metrics,
+            serde_json::json!({
+                "dead_lettered": 1,
+                "rejected": 2,
+                "dead_letter_failures": 1,
+            })
+        );
+    }
+}
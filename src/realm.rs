@@ -0,0 +1,42 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::types::RealmId;
+
+/// How long to trust a cached realm lookup. We aren't told when realms are
+/// created, renamed or deleted, so entries must eventually expire.
+const REALM_CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct RealmCache {
+    realm_ids: Mutex<HashMap<String, (Instant, Option<RealmId>)>>,
+}
+
+impl RealmCache {
+    /// Look up the ID of the realm with the given subdomain, if it exists.
+    pub async fn get_realm_id(
+        &self,
+        db_pool: &deadpool_postgres::Pool,
+        subdomain: &str,
+    ) -> Result<Option<RealmId>> {
+        if let Some(&(fetched, realm_id)) = self.realm_ids.lock().unwrap().get(subdomain) {
+            if fetched.elapsed() < REALM_CACHE_TTL {
+                return Ok(realm_id);
+            }
+        }
+
+        let db = db_pool.get().await?;
+        let sql = "SELECT id FROM zerver_realm WHERE string_id = $1";
+        let realm_id = db
+            .query_opt(sql, &[&subdomain])
+            .await?
+            .map(|realm_row| realm_row.get("id"));
+
+        let mut realm_ids = self.realm_ids.lock().unwrap();
+        realm_ids.retain(|_, &mut (fetched, _)| fetched.elapsed() < REALM_CACHE_TTL);
+        realm_ids.insert(subdomain.to_string(), (Instant::now(), realm_id));
+        Ok(realm_id)
+    }
+}
@@ -0,0 +1,90 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// How many processed notices to remember. Anything RabbitMQ redelivers was
+/// unacknowledged when its connection dropped, so this only needs to
+/// comfortably exceed the prefetch count.
+const RECENT_NOTICES_CAPACITY: usize = 10_000;
+
+type NoticeDigest = [u8; 32];
+
+#[derive(Default)]
+struct RecentNoticesInner {
+    digests: HashSet<NoticeDigest>,
+    order: VecDeque<NoticeDigest>,
+}
+
+/// Remembers the notices we've recently processed, so that a notice
+/// redelivered after a lost ack isn't delivered to clients or published to
+/// the missed-message queues twice.
+///
+/// This deliberately isn't saved anywhere: after a crash, the event queues
+/// and the missed-message outbox are lost too, so the notices RabbitMQ
+/// redelivers must be processed again rather than skipped.
+#[derive(Default)]
+pub struct RecentNotices {
+    inner: Mutex<RecentNoticesInner>,
+}
+
+fn digest(data: &[u8]) -> NoticeDigest {
+    Sha256::digest(data).into()
+}
+
+impl RecentNotices {
+    /// Whether a delivery is a redelivery of a notice we already processed.
+    pub fn is_duplicate(&self, redelivered: bool, data: &[u8]) -> bool {
+        redelivered && self.inner.lock().unwrap().digests.contains(&digest(data))
+    }
+
+    pub fn insert(&self, data: &[u8]) {
+        let digest = digest(data);
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.digests.insert(digest) {
+            return;
+        }
+        inner.order.push_back(digest);
+        if inner.order.len() > RECENT_NOTICES_CAPACITY {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.digests.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecentNotices, RECENT_NOTICES_CAPACITY};
+
+    #[test]
+    fn skips_redelivered_notices() {
+        let recent_notices = RecentNotices::default();
+        recent_notices.insert(b"processed");
+        assert!(recent_notices.is_duplicate(true, b"processed"));
+        assert!(!recent_notices.is_duplicate(false, b"processed"));
+        assert!(!recent_notices.is_duplicate(true, b"unprocessed"));
+    }
+
+    #[test]
+    fn forgets_oldest_notices() {
+        let recent_notices = RecentNotices::default();
+        recent_notices.insert(b"first");
+        for i in 0..RECENT_NOTICES_CAPACITY {
+            recent_notices.insert(i.to_string().as_bytes());
+        }
+        assert!(!recent_notices.is_duplicate(true, b"first"));
+        assert!(recent_notices.is_duplicate(true, b"0"));
+    }
+
+    #[test]
+    fn processes_redeliveries_after_restart() {
+        // A crash loses whatever outbox entries the notice created, so the
+        // restarted process must publish them again.
+        let recent_notices = RecentNotices::default();
+        recent_notices.insert(b"processed before crash");
+        drop(recent_notices);
+
+        let recent_notices = RecentNotices::default();
+        assert!(!recent_notices.is_duplicate(true, b"processed before crash"));
+    }
+}
@@ -25,6 +25,11 @@ pub enum EmptyMsg {
 pub enum ErrorCode {
     BadRequest,
     BadEventQueueId,
+    Unauthorized,
+    InvalidApiKey,
+    UserDeactivated,
+    RealmDeactivated,
+    CsrfFailed,
 }
 
 #[derive(Debug, Serialize)]
@@ -0,0 +1,188 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::notice::ClientEvent;
+use crate::queues::Queues;
+
+pub struct ServerVersion {
+    pub zulip_version: Option<String>,
+    pub zulip_merge_base: Option<String>,
+    pub zulip_feature_level: Option<i32>,
+    /// Identifies this run of the server; clients compare it to detect
+    /// restarts.
+    pub server_generation: i64,
+}
+
+/// Parse a Python string or integer literal from Zulip's `version.py`.
+fn parse_version_literal(value: &str) -> Option<Value> {
+    let value = value.split('#').next()?.trim();
+    for quote in ['"', '\''] {
+        if let Some(string) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return Some(Value::from(string));
+        }
+    }
+    value.parse::<i32>().ok().map(Value::from)
+}
+
+impl ServerVersion {
+    pub fn new(
+        zulip_version: Option<String>,
+        zulip_merge_base: Option<String>,
+        zulip_feature_level: Option<i32>,
+    ) -> Self {
+        let server_generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .try_into()
+            .unwrap_or(i64::MAX);
+        ServerVersion {
+            zulip_version,
+            zulip_merge_base,
+            zulip_feature_level,
+            server_generation,
+        }
+    }
+
+    /// Fill in whatever we weren't told from the contents of Zulip's
+    /// `version.py`.
+    fn fill_from_version_py(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            let Some(value) = parse_version_literal(value) else {
+                continue;
+            };
+            match name.trim() {
+                "ZULIP_VERSION" if self.zulip_version.is_none() => {
+                    self.zulip_version = value.as_str().map(str::to_string);
+                }
+                "ZULIP_MERGE_BASE" if self.zulip_merge_base.is_none() => {
+                    self.zulip_merge_base = value.as_str().map(str::to_string);
+                }
+                "API_FEATURE_LEVEL" if self.zulip_feature_level.is_none() => {
+                    self.zulip_feature_level =
+                        value.as_i64().and_then(|level| level.try_into().ok());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Fill in whatever we weren't told from Zulip's `version.py` at `path`.
+    pub fn fill_from_version_file(&mut self, path: &Path) -> Result<()> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+        self.fill_from_version_py(&contents);
+        Ok(())
+    }
+}
+
+/// Tell every client that the server has restarted, so that web clients
+/// reload if the server has changed. Called for queues restored from a
+/// previous run.
+pub fn send_restart_events(queues: &mut Queues, version: &ServerVersion, immediate: bool) {
+    let mut attrs = HashMap::from([(
+        "server_generation".to_string(),
+        Value::from(version.server_generation),
+    )]);
+    // Clients treat a missing field as unknown, so leave out what we don't
+    // know rather than sending something made up.
+    if let Some(zulip_version) = &version.zulip_version {
+        attrs.insert("zulip_version".to_string(), Value::from(&**zulip_version));
+    }
+    if let Some(zulip_merge_base) = &version.zulip_merge_base {
+        attrs.insert(
+            "zulip_merge_base".to_string(),
+            Value::from(&**zulip_merge_base),
+        );
+    }
+    if let Some(zulip_feature_level) = version.zulip_feature_level {
+        attrs.insert(
+            "zulip_feature_level".to_string(),
+            Value::from(zulip_feature_level),
+        );
+    }
+    if immediate {
+        attrs.insert("immediate".to_string(), Value::from(true));
+    }
+    let event = ClientEvent::Other {
+        r#type: "restart".to_string(),
+        attrs: Arc::new(attrs),
+    };
+
+    for client in queues.iter_mut() {
+        if client.accepts_event(&event) {
+            client.add_event(event.clone());
+        }
+    }
+}
+
+fn is_web_reload_client(event: &ClientEvent) -> bool {
+    matches!(event, ClientEvent::Other { r#type, .. } if r#type == "web_reload_client")
+}
+
+/// Ask up to `count` web clients (or all of them) to reload, returning how many
+/// were sent the event. Clients that already have a reload event pending are
+/// skipped, so that repeated calls make progress through all clients.
+pub fn send_web_reload_client_events(
+    queues: &mut Queues,
+    immediate: bool,
+    count: Option<usize>,
+) -> usize {
+    let event = ClientEvent::Other {
+        r#type: "web_reload_client".to_string(),
+        attrs: Arc::new(HashMap::from([(
+            "immediate".to_string(),
+            Value::from(immediate),
+        )])),
+    };
+
+    let mut sent = 0;
+    for client in queues.iter_mut() {
+        if count.is_some_and(|count| sent >= count) {
+            break;
+        }
+        if client.accepts_event(&event) && !client.queue.contents().any(is_web_reload_client) {
+            client.add_event(event.clone());
+            sent += 1;
+        }
+    }
+    sent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServerVersion;
+
+    #[test]
+    fn fills_from_version_py() {
+        let mut version = ServerVersion::new(Some("9.0".to_string()), None, None);
+        version.fill_from_version_py(
+            r#"import os
+
+ZULIP_VERSION = "10.0-dev+git"
+LATEST_MAJOR_VERSION = "9.0"
+ZULIP_MERGE_BASE = "9.0-1234-gabcdef"
+
+# Bump the minor PROVISION_VERSION to indicate that folks should provision
+API_FEATURE_LEVEL = 301  # Last bumped for ...
+"#,
+        );
+        assert_eq!(version.zulip_version.as_deref(), Some("9.0"));
+        assert_eq!(
+            version.zulip_merge_base.as_deref(),
+            Some("9.0-1234-gabcdef")
+        );
+        assert_eq!(version.zulip_feature_level, Some(301));
+    }
+}
@@ -4,13 +4,14 @@ use configparser::ini::Ini;
 pub struct Secrets {
     pub local_database_password: String,
     pub rabbitmq_password: String,
+    pub rabbitmq_tls_client_identity_password: Option<String>,
     pub secret_key: String,
     pub shared_secret: String,
     pub avatar_salt: String,
 }
 
 impl Secrets {
-    pub fn load(secrets_file: String) -> Result<Secrets> {
+    pub fn load(secrets_file: &str) -> Result<Secrets> {
         let mut secrets_config = Ini::new();
         secrets_config.load(secrets_file).map_err(Error::msg)?;
         Ok(Secrets {
@@ -20,6 +21,8 @@ impl Secrets {
             rabbitmq_password: secrets_config
                 .get("secrets", "rabbitmq_password")
                 .with_context(|| "missing rabbitmq_password")?,
+            rabbitmq_tls_client_identity_password: secrets_config
+                .get("secrets", "rabbitmq_tls_client_identity_password"),
             secret_key: secrets_config
                 .get("secrets", "secret_key")
                 .with_context(|| "missing secret_key")?,
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+/// The subdomain of a realm hosted on the root domain.
+pub const SUBDOMAIN_FOR_ROOT_DOMAIN: &str = "";
+
+pub struct SubdomainSettings {
+    pub external_host: String,
+    /// Realms served on hosts that aren't subdomains of `external_host`, by
+    /// subdomain.
+    pub realm_hosts: HashMap<String, String>,
+    /// Subdomains of `external_host` that refer to the root domain realm.
+    pub root_subdomain_aliases: Vec<String>,
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            hostname
+        }
+        _ => host,
+    }
+}
+
+/// Whether `host` is `realm_host`, optionally followed by a port.
+fn matches_host(host: &str, realm_host: &str) -> bool {
+    host == realm_host || strip_port(host) == realm_host
+}
+
+impl SubdomainSettings {
+    /// Get the subdomain of the realm that `host` (the value of a `Host`
+    /// header) refers to, like Zulip's `get_subdomain_from_hostname`.
+    pub fn get_subdomain(&self, host: &str) -> String {
+        let host = host.to_lowercase();
+        let external_host = self.external_host.to_lowercase();
+        if let Some(subdomain) = [&*host, strip_port(&host)]
+            .into_iter()
+            .find_map(|host| host.strip_suffix(&*external_host)?.strip_suffix('.'))
+        {
+            if self
+                .root_subdomain_aliases
+                .iter()
+                .any(|alias| alias == subdomain)
+            {
+                return SUBDOMAIN_FOR_ROOT_DOMAIN.to_string();
+            }
+            return subdomain.to_string();
+        }
+
+        for (subdomain, realm_host) in &self.realm_hosts {
+            if matches_host(&host, &realm_host.to_lowercase()) {
+                return subdomain.clone();
+            }
+        }
+
+        SUBDOMAIN_FOR_ROOT_DOMAIN.to_string()
+    }
+}
@@ -3,6 +3,7 @@ use std::collections::HashSet;
 pub type RealmId = i32;
 pub type UserId = i32;
 pub type UserGroupId = i32;
+pub type StreamId = i32;
 pub type MessageId = i64;
 pub type MessageFlag = String;
 pub type MessageFlags = HashSet<MessageFlag>;
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::app_state::AppState;
+use crate::types::UserId;
+
+const USER_ACTIVITY_FLUSH_FREQ: Duration = Duration::from_secs(5);
+
+/// The longest client name that fits in `zerver_client`.
+const MAX_CLIENT_NAME_LENGTH: usize = 30;
+
+type ClientId = i32;
+
+struct PendingActivity {
+    user_profile_id: UserId,
+    client_name: String,
+    query: Cow<'static, str>,
+    time: i64,
+}
+
+#[derive(Serialize)]
+struct UserActivityEvent<'a> {
+    query: &'a str,
+    user_profile_id: UserId,
+    time: i64,
+    client_id: ClientId,
+    // For workers that predate client_id.
+    client: &'a str,
+}
+
+/// Buffers `UserActivity` records so that they can be published to the
+/// `user_activity` queue in batches, rather than once per request.
+#[derive(Default)]
+pub struct UserActivity {
+    pending: Mutex<Vec<PendingActivity>>,
+    client_ids: Mutex<HashMap<String, ClientId>>,
+}
+
+impl UserActivity {
+    pub fn record(&self, user_profile_id: UserId, client_name: &str, query: Cow<'static, str>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .try_into()
+            .unwrap_or(i64::MAX);
+        self.pending.lock().unwrap().push(PendingActivity {
+            user_profile_id,
+            client_name: client_name.chars().take(MAX_CLIENT_NAME_LENGTH).collect(),
+            query,
+            time,
+        });
+    }
+
+    /// Like Zulip's `get_client`, creating the client if it doesn't exist.
+    async fn get_client_id(
+        &self,
+        db_pool: &deadpool_postgres::Pool,
+        client_name: &str,
+    ) -> Result<ClientId> {
+        if let Some(&client_id) = self.client_ids.lock().unwrap().get(client_name) {
+            return Ok(client_id);
+        }
+
+        let db = db_pool.get().await?;
+        let sql = "INSERT INTO zerver_client (name) VALUES ($1) ON CONFLICT (name) DO NOTHING";
+        db.execute(sql, &[&client_name]).await?;
+        let sql = "SELECT id FROM zerver_client WHERE name = $1";
+        let client_id: ClientId = db.query_one(sql, &[&client_name]).await?.get("id");
+
+        self.client_ids
+            .lock()
+            .unwrap()
+            .insert(client_name.to_string(), client_id);
+        Ok(client_id)
+    }
+
+    /// Put records we failed to publish back, ahead of any recorded since.
+    fn restore(&self, unsent: Vec<PendingActivity>) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.splice(0..0, unsent);
+    }
+
+    async fn serialize(
+        &self,
+        db_pool: &deadpool_postgres::Pool,
+        pending: &[PendingActivity],
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut payloads = Vec::with_capacity(pending.len());
+        for activity in pending {
+            let client_id = self.get_client_id(db_pool, &activity.client_name).await?;
+            payloads.push(serde_json::to_vec(&UserActivityEvent {
+                query: &activity.query,
+                user_profile_id: activity.user_profile_id,
+                time: activity.time,
+                client_id,
+                client: &activity.client_name,
+            })?);
+        }
+        Ok(payloads)
+    }
+
+    async fn flush(&self, state: &AppState) -> Result<()> {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let payloads = match self.serialize(&state.db_pool, &pending).await {
+            Ok(payloads) => payloads,
+            Err(err) => {
+                self.restore(pending);
+                return Err(err);
+            }
+        };
+
+        // Zulip's worker expects one record per message, so pipeline them.
+        let messages: Vec<(&str, &[u8])> = payloads
+            .iter()
+            .map(|payload| ("user_activity", &payload[..]))
+            .collect();
+        let results = state.rabbitmq.publish_all(&messages).await;
+
+        let mut unsent = vec![];
+        let mut first_error = None;
+        for (activity, result) in pending.into_iter().zip(results) {
+            if let Err(err) = result {
+                unsent.push(activity);
+                first_error.get_or_insert(err);
+            }
+        }
+        self.restore(unsent);
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+/// Periodically publish recorded user activity, and once more at shutdown.
+pub async fn run(state: Arc<AppState>) {
+    let mut shutdown_rx = state.shutdown_rx.clone();
+    let mut interval = tokio::time::interval(USER_ACTIVITY_FLUSH_FREQ);
+    loop {
+        let shutdown = tokio::select! {
+            _ = interval.tick() => false,
+            () = shutdown_rx.wait() => true,
+        };
+        if let Err(err) = state.user_activity.flush(&state).await {
+            tracing::error!("failed to publish user activity: {err:#}");
+        }
+        if shutdown {
+            break;
+        }
+    }
+}
@@ -0,0 +1,209 @@
+/// The first ZulipMobile version that can render `PRONOUNS` custom profile
+/// fields.
+const FIRST_VERSION_TO_SUPPORT_PRONOUNS_FIELD: &str = "27.192";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct UserAgent<'a> {
+    pub name: &'a str,
+    pub version: Option<&'a str>,
+}
+
+/// Parse a User-Agent string the way Zulip's `parse_user_agent` does, which
+/// matches it against
+///
+/// ```text
+/// ^ (?P<name> [^/ ]* [^0-9/(]* ) (/ (?P<version> [^/ ]* ))? ([ /] .*)? $
+/// ```
+pub fn parse_user_agent(user_agent: &str) -> UserAgent<'_> {
+    let bytes = user_agent.as_bytes();
+    // The first part of the name always ends at the first '/' or ' ', which
+    // the rest of the pattern can always match from.
+    let start = bytes
+        .iter()
+        .position(|&b| matches!(b, b'/' | b' '))
+        .unwrap_or(bytes.len());
+    // The second part extends as far as it can while leaving a valid rest.
+    let limit = bytes[start..]
+        .iter()
+        .position(|&b| b.is_ascii_digit() || matches!(b, b'/' | b'('))
+        .map_or(bytes.len(), |i| start + i);
+    let end = (start..=limit)
+        .rev()
+        .find(|&i| i == bytes.len() || matches!(bytes[i], b'/' | b' '))
+        .unwrap_or(start);
+
+    let name = &user_agent[..end];
+    let version = user_agent[end..].strip_prefix('/').map(|rest| {
+        rest.find(['/', ' '])
+            .map_or(rest, |version_end| &rest[..version_end])
+    });
+    UserAgent { name, version }
+}
+
+/// Determine the client name for a request, like Zulip's `parse_client` and
+/// `process_client`.
+pub fn parse_client(user_agent: Option<&str>, is_browser_view: bool) -> String {
+    let Some(user_agent) = user_agent else {
+        return "Unspecified".to_string();
+    };
+    let name = parse_user_agent(user_agent).name;
+    // Avoid changing the client string for browsers, but let the Zulip
+    // desktop apps be themselves.
+    if is_browser_view && !name.starts_with("Zulip") {
+        "website".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Old mobile app versions break on custom profile fields of type `PRONOUNS`,
+/// so they get them as `SHORT_TEXT` instead.
+pub fn is_pronouns_field_type_supported(user_agent: Option<&str>) -> bool {
+    let Some(user_agent) = user_agent else {
+        return true;
+    };
+    let user_agent = parse_user_agent(user_agent);
+    if user_agent.name != "ZulipMobile" {
+        return true;
+    }
+    user_agent.version.is_none_or(|version| {
+        version_lt(version, FIRST_VERSION_TO_SUPPORT_PRONOUNS_FIELD) != Some(true)
+    })
+}
+
+/// Split a version string into its leading dotted numerals and the rest.
+fn pop_numerals(version: &str) -> (Vec<u64>, &str) {
+    let mut numbers = vec![];
+    let mut rest = version;
+    loop {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Ok(number) = rest[..digits].parse() else {
+            break;
+        };
+        numbers.push(number);
+        rest = &rest[digits..];
+        match rest.strip_prefix('.') {
+            Some(next) if next.starts_with(|c: char| c.is_ascii_digit()) => rest = next,
+            _ => break,
+        }
+    }
+    (numbers, rest)
+}
+
+/// Compare two Zulip-style version strings, like Zulip's `version_lt`.
+///
+/// Returns `None` if either version is not parseable.
+pub fn version_lt(ver1: &str, ver2: &str) -> Option<bool> {
+    let (num1, rest1) = pop_numerals(ver1);
+    let (num2, rest2) = pop_numerals(ver2);
+    if num1.is_empty() || num2.is_empty() {
+        return None;
+    }
+    let common_len = num1.len().min(num2.len());
+    let (common_num1, rest_num1) = num1.split_at(common_len);
+    let (common_num2, rest_num2) = num2.split_at(common_len);
+
+    // Leading numbers win.
+    if common_num1 != common_num2 {
+        return Some(common_num1 < common_num2);
+    }
+
+    // More numbers beats end-of-string, but we can't compare them with
+    // trailing text.
+    if rest1.is_empty() && !rest_num2.is_empty() {
+        return Some(true);
+    }
+    if !rest_num1.is_empty() && rest2.is_empty() {
+        return Some(false);
+    }
+    if !rest_num1.is_empty() || !rest_num2.is_empty() {
+        return None;
+    }
+
+    // The numbers match, and we can't compare trailing text.
+    if !rest1.is_empty() || !rest2.is_empty() {
+        return None;
+    }
+    Some(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_pronouns_field_type_supported, parse_client, parse_user_agent, version_lt, UserAgent,
+    };
+
+    #[test]
+    fn parses_user_agents() {
+        for (user_agent, name, version) in [
+            (
+                "ZulipMobile/26.22.145 (Android 10)",
+                "ZulipMobile",
+                Some("26.22.145"),
+            ),
+            (
+                "ZulipElectron/5.9.3 Mozilla/5.0 (X11; Linux x86_64)",
+                "ZulipElectron",
+                Some("5.9.3"),
+            ),
+            ("Mozilla/5.0 (X11; Linux x86_64)", "Mozilla", Some("5.0")),
+            ("Zulip Desktop/1.2", "Zulip Desktop", Some("1.2")),
+            ("python-zulip-bot/0.1", "python-zulip-bot", Some("0.1")),
+            ("ZulipInvalid", "ZulipInvalid", None),
+            ("curl 7.0", "curl", None),
+            ("", "", None),
+        ] {
+            assert_eq!(
+                parse_user_agent(user_agent),
+                UserAgent { name, version },
+                "{user_agent:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(version_lt("1.2.3", "1.2.4"), Some(true));
+        assert_eq!(version_lt("1.10", "1.9"), Some(false));
+        assert_eq!(version_lt("1.2", "1.2.0"), Some(true));
+        assert_eq!(version_lt("4.0", "4.0"), Some(false));
+        assert_eq!(version_lt("1.2.1", "1.2"), Some(false));
+        assert_eq!(version_lt("4.0-beta", "4.1"), Some(true));
+        assert_eq!(version_lt("4.0-beta", "4.0"), None);
+        assert_eq!(version_lt("4.0", "4.0-beta"), None);
+        assert_eq!(version_lt("4.0-beta", "4.0-rc1"), None);
+        assert_eq!(version_lt("1.2-beta", "1.2.0"), None);
+        assert_eq!(version_lt("1.2.0", "1.2-beta"), None);
+        assert_eq!(version_lt("x", "1"), None);
+    }
+
+    #[test]
+    fn pronouns_support() {
+        assert!(is_pronouns_field_type_supported(None));
+        assert!(is_pronouns_field_type_supported(Some("Mozilla/5.0")));
+        assert!(is_pronouns_field_type_supported(Some("ZulipMobile")));
+        assert!(is_pronouns_field_type_supported(Some(
+            "ZulipMobile/27.192 (iOS 16)"
+        )));
+        assert!(is_pronouns_field_type_supported(Some(
+            "ZulipMobile/27.192-beta (iOS 16)"
+        )));
+        assert!(!is_pronouns_field_type_supported(Some(
+            "ZulipMobile/27.191 (iOS 16)"
+        )));
+    }
+
+    #[test]
+    fn client_names() {
+        assert_eq!(parse_client(None, false), "Unspecified");
+        assert_eq!(parse_client(Some("Mozilla/5.0 (X11)"), true), "website");
+        assert_eq!(
+            parse_client(Some("ZulipElectron/5.9.3 Mozilla/5.0"), true),
+            "ZulipElectron"
+        );
+        assert_eq!(
+            parse_client(Some("ZulipMobile/27.192 (iOS 16)"), false),
+            "ZulipMobile"
+        );
+    }
+}
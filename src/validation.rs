@@ -0,0 +1,407 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::notice::{notice_schema_violations, Notice};
+
+/// Describe how `actual` differs from `expected`, field by field, appending
+/// to `diffs`. A `null` field counts the same as a missing one.
+pub fn json_diff(path: &str, expected: &Value, actual: &Value, diffs: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, actual_value) in actual {
+                match expected.get(key) {
+                    Some(expected_value) => json_diff(
+                        &format!("{path}.{key}"),
+                        expected_value,
+                        actual_value,
+                        diffs,
+                    ),
+                    None if actual_value.is_null() => {}
+                    None => diffs.push(format!("{path}.{key}: unexpected field")),
+                }
+            }
+            for (key, expected_value) in expected {
+                if !actual.contains_key(key) && !expected_value.is_null() {
+                    diffs.push(format!("{path}.{key}: missing field"));
+                }
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) if expected.len() == actual.len() => {
+            for (index, (expected_value, actual_value)) in expected.iter().zip(actual).enumerate() {
+                json_diff(
+                    &format!("{path}[{index}]"),
+                    expected_value,
+                    actual_value,
+                    diffs,
+                );
+            }
+        }
+        _ if expected == actual => {}
+        _ => diffs.push(format!("{path}: expected {expected}, got {actual}")),
+    }
+}
+
+/// The fields Django may send in a `message` event, besides `message_dict`'s.
+const MESSAGE_EVENT_FIELDS: &[&str] = &[
+    "message_dict",
+    "sender_queue_id",
+    "stream_name",
+    "invite_only",
+    "realm_id",
+    "realm_host",
+    "local_id",
+    "presence_idle_user_ids",
+    "online_push_user_ids",
+    "dm_mention_push_disabled_user_ids",
+    "dm_mention_email_disabled_user_ids",
+    "pm_mention_push_disabled_user_ids",
+    "pm_mention_email_disabled_user_ids",
+    "stream_push_user_ids",
+    "stream_email_user_ids",
+    "topic_wildcard_mention_user_ids",
+    "stream_wildcard_mention_user_ids",
+    "wildcard_mention_user_ids",
+    "followed_topic_push_user_ids",
+    "followed_topic_email_user_ids",
+    "topic_wildcard_mention_in_followed_topic_user_ids",
+    "stream_wildcard_mention_in_followed_topic_user_ids",
+    "muted_sender_user_ids",
+    "all_bot_user_ids",
+    "disable_external_notifications",
+];
+
+/// The fields of a `message` event's `message_dict`, after Zulip's
+/// `MessageDict.wide_dict`.
+pub const MESSAGE_DICT_FIELDS: &[&str] = &[
+    "id",
+    "sender_id",
+    "sender_email",
+    "sender_delivery_email",
+    "sender_full_name",
+    "sender_realm_id",
+    "sender_realm_str",
+    "sender_avatar_source",
+    "sender_avatar_version",
+    "sender_is_mirror_dummy",
+    "sender_email_address_visibility",
+    "client",
+    "content",
+    "rendered_content",
+    "rendered_content_version",
+    "content_type",
+    "avatar_url",
+    "type",
+    "display_recipient",
+    "recipient_id",
+    "recipient_type",
+    "recipient_type_id",
+    "stream_id",
+    "subject",
+    "topic",
+    "topic_links",
+    "subject_links",
+    "timestamp",
+    "is_me_message",
+    "reactions",
+    "submessages",
+    "edit_history",
+    "last_edit_timestamp",
+    "last_moved_timestamp",
+    "invite_only_stream",
+];
+
+const UPDATE_MESSAGE_EVENT_FIELDS: &[&str] = &[
+    "user_id",
+    "edit_timestamp",
+    "message_id",
+    "message_ids",
+    "rendering_only",
+    "flags",
+    "stream_name",
+    "stream_id",
+    "new_stream_id",
+    "propagate_mode",
+    "orig_subject",
+    "subject",
+    "topic_links",
+    "orig_content",
+    "orig_rendered_content",
+    "content",
+    "rendered_content",
+    "is_me_message",
+    "prior_mention_user_ids",
+    "presence_idle_user_ids",
+    "online_push_user_ids",
+    "push_notify_user_ids",
+    "dm_mention_push_disabled_user_ids",
+    "dm_mention_email_disabled_user_ids",
+    "pm_mention_push_disabled_user_ids",
+    "pm_mention_email_disabled_user_ids",
+    "stream_push_user_ids",
+    "stream_email_user_ids",
+    "topic_wildcard_mention_user_ids",
+    "stream_wildcard_mention_user_ids",
+    "wildcard_mention_user_ids",
+    "followed_topic_push_user_ids",
+    "followed_topic_email_user_ids",
+    "topic_wildcard_mention_in_followed_topic_user_ids",
+    "stream_wildcard_mention_in_followed_topic_user_ids",
+    "muted_sender_user_ids",
+    "all_bot_user_ids",
+    "disable_external_notifications",
+];
+
+/// The fields besides `type` that Django may send in an event of the given
+/// type and `op`, or `None` if we don't know that kind of event.
+fn known_event_fields(event_type: &str, op: Option<&str>) -> Option<&'static [&'static str]> {
+    Some(match (event_type, op) {
+        ("message", _) => MESSAGE_EVENT_FIELDS,
+        ("update_message", _) => UPDATE_MESSAGE_EVENT_FIELDS,
+        ("delete_message", _) => &[
+            "message_type",
+            "message_id",
+            "message_ids",
+            "stream_id",
+            "topic",
+        ],
+        ("presence", _) => &["user_id", "email", "server_timestamp", "presence"],
+        ("custom_profile_fields", _) => &["fields"],
+        ("cleanup_queue", _) => &["queue_id"],
+        ("stream", Some("create")) => &["op", "streams"],
+        ("stream", Some("delete")) => &["op", "streams", "stream_ids"],
+        ("stream", Some("update")) => &[
+            "op",
+            "stream_id",
+            "name",
+            "property",
+            "value",
+            "rendered_description",
+            "history_public_to_subscribers",
+            "is_web_public",
+        ],
+        ("subscription", Some("add" | "remove")) => &["op", "subscriptions"],
+        ("subscription", Some("update")) => &["op", "stream_id", "property", "value"],
+        ("subscription", Some("peer_add" | "peer_remove")) => &["op", "stream_ids", "user_ids"],
+        ("realm_user", Some("add" | "remove" | "update")) => &["op", "person"],
+        ("realm", Some("update")) => &["op", "property", "value", "extra_data"],
+        ("realm", Some("update_dict")) => &["op", "property", "data"],
+        ("realm", Some("deactivated")) => &["op", "realm_id"],
+        ("reaction", Some("add" | "remove")) => &[
+            "op",
+            "message_id",
+            "emoji_name",
+            "emoji_code",
+            "reaction_type",
+            "user_id",
+            "user",
+        ],
+        ("typing", Some("start" | "stop")) => &[
+            "op",
+            "message_type",
+            "sender",
+            "recipients",
+            "stream_id",
+            "topic",
+        ],
+        ("update_message_flags", _) => &[
+            "op",
+            "operation",
+            "flag",
+            "messages",
+            "all",
+            "message_details",
+        ],
+        ("user_topic", _) => &[
+            "stream_id",
+            "topic_name",
+            "last_updated",
+            "visibility_policy",
+        ],
+        ("user_settings", Some("update")) => &["op", "property", "value", "language_name"],
+        ("user_group", Some("add")) => &["op", "group"],
+        ("user_group", Some("update")) => &["op", "group_id", "data"],
+        ("user_group", Some("add_members" | "remove_members")) => &["op", "group_id", "user_ids"],
+        ("user_group", Some("add_subgroups" | "remove_subgroups")) => {
+            &["op", "group_id", "direct_subgroup_ids"]
+        }
+        ("user_group", Some("remove")) => &["op", "group_id"],
+        ("realm_linkifiers", _) => &["realm_linkifiers"],
+        _ => return None,
+    })
+}
+
+/// Report each field of the object `actual` that isn't in `known`, appending
+/// to `diffs`. Our models pass fields they don't name through to clients, so
+/// this is how we notice that Django has started sending something new.
+pub fn unexpected_fields(path: &str, actual: &Value, known: &[&str], diffs: &mut Vec<String>) {
+    let Value::Object(actual) = actual else {
+        return;
+    };
+    for key in actual.keys() {
+        if !known.contains(&key.as_str()) {
+            diffs.push(format!("{path}.{key}: unexpected field"));
+        }
+    }
+}
+
+/// Report the top-level fields of `event` that Django doesn't send for its
+/// type, if it's a type we know.
+pub fn unexpected_event_fields(event_type: &str, event: &Value, diffs: &mut Vec<String>) {
+    let op = event.get("op").and_then(Value::as_str);
+    let Some(known) = known_event_fields(event_type, op) else {
+        return;
+    };
+    let Value::Object(event) = event else {
+        return;
+    };
+    for key in event.keys() {
+        if key != "type" && !known.contains(&key.as_str()) {
+            diffs.push(format!("event.{key}: unexpected field"));
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ValidationMetrics {
+    strict: bool,
+    violations: HashMap<String, u64>,
+}
+
+/// In strict mode, checks every incoming notice against the schema we expect
+/// for its event type, to catch version skew between Django and boq.
+/// Violations are logged and counted per event type, with the counts
+/// reported by `/api/internal/rabbitmq_status`; delivery is unaffected.
+pub struct NoticeValidator {
+    strict: bool,
+    violation_counts: Mutex<HashMap<String, u64>>,
+}
+
+impl NoticeValidator {
+    pub fn new(strict: bool) -> NoticeValidator {
+        NoticeValidator {
+            strict,
+            violation_counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn metrics(&self) -> ValidationMetrics {
+        ValidationMetrics {
+            strict: self.strict,
+            violations: self.violation_counts.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn check(&self, notice: &Notice) {
+        if !self.strict {
+            return;
+        }
+
+        let (event_type, violations) = notice_schema_violations(notice);
+        if violations.is_empty() {
+            return;
+        }
+
+        let count = {
+            let mut violation_counts = self.violation_counts.lock().unwrap();
+            let count = violation_counts.entry(event_type.clone()).or_default();
+            *count += 1;
+            *count
+        };
+        for violation in &violations {
+            tracing::warn!("schema violation in {event_type} notice: {violation}");
+        }
+        tracing::warn!("{count} {event_type} notices have failed validation so far");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_diff, NoticeValidator};
+    use crate::notice::{notice_schema_violations, Notice};
+    use serde_json::json;
+
+    fn check(notice: &str) -> (String, Vec<String>) {
+        let notice: Notice = serde_json::from_str(notice).unwrap();
+        notice_schema_violations(&notice)
+    }
+
+    #[test]
+    fn json_diff_reports_fields() {
+        let mut diffs = vec![];
+        json_diff(
+            "event",
+            &json!({"a": 1, "b": [1, 2], "c": null, "d": "x"}),
+            &json!({"a": 2, "b": [1, 2], "e": true}),
+            &mut diffs,
+        );
+        diffs.sort();
+        assert_eq!(
+            diffs,
+            [
+                "event.a: expected 1, got 2",
+                "event.d: missing field",
+                "event.e: unexpected field",
+            ]
+        );
+    }
+
+    #[test]
+    fn valid_notices() {
+        for notice in [
+            r#"{"event": {"type": "delete_message", "message_type": "stream", "message_ids": [1, 2], "stream_id": 3, "topic": "t"}, "users": [10]}"#,
+            r#"{"event": {"type": "presence", "user_id": 10, "email": "a@example.com", "server_timestamp": 1.5, "presence": {"website": {"status": "active"}}}, "users": [11]}"#,
+            r#"{"event": {"type": "update_message", "user_id": 10, "message_id": 5, "rendering_only": false, "edit_timestamp": 1, "content": "x", "rendered_content": "<p>x</p>"}, "users": [{"id": 10, "flags": []}]}"#,
+            r#"{"event": {"type": "user_topic", "stream_id": 1, "topic_name": "t", "last_updated": 1, "visibility_policy": 3}, "users": [10]}"#,
+            r#"{"event": {"type": "has_zoom_token", "value": true}, "users": [10]}"#,
+        ] {
+            let (_, violations) = check(notice);
+            assert_eq!(violations, Vec::<String>::new(), "{notice}");
+        }
+    }
+
+    #[test]
+    fn unexpected_fields_are_reported() {
+        let (event_type, violations) = check(
+            r#"{"event": {"type": "delete_message", "message_ids": [1], "surprise": 1}, "users": [10]}"#,
+        );
+        assert_eq!(event_type, "delete_message");
+        assert_eq!(violations, ["event.surprise: unexpected field"]);
+
+        let (_, violations) = check(
+            r#"{"event": {"type": "subscription", "op": "update", "stream_id": 1, "property": "pin_to_top", "value": true, "surprise": 1}, "users": [10]}"#,
+        );
+        assert_eq!(violations, ["event.surprise: unexpected field"]);
+    }
+
+    #[test]
+    fn parse_failures_are_reported() {
+        let (event_type, violations) = check(
+            r#"{"event": {"type": "user_topic", "stream_id": "one", "topic_name": "t", "last_updated": 1, "visibility_policy": 3}, "users": [10]}"#,
+        );
+        assert_eq!(event_type, "user_topic");
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with("event: "), "{violations:?}");
+
+        let (_, violations) = check(r#"{"event": {"type": "presence"}, "users": [10]}"#);
+        assert_eq!(violations.len(), 1);
+    }
+
+    #[test]
+    fn counts_violations_per_type() {
+        let validator = NoticeValidator::new(true);
+        for _ in 0..2 {
+            let notice: Notice = serde_json::from_str(
+                r#"{"event": {"type": "cleanup_queue", "queue_id": "x", "surprise": 1}, "users": [10]}"#,
+            )
+            .unwrap();
+            validator.check(&notice);
+        }
+        let metrics = serde_json::to_value(validator.metrics()).unwrap();
+        assert_eq!(
+            metrics,
+            json!({"strict": true, "violations": {"cleanup_queue": 2}})
+        );
+    }
+}